@@ -1,53 +1,373 @@
-use clap::{builder::ArgPredicate, Parser, Subcommand};
-use std::path::PathBuf;
-use tracing::Level;
+use clap::{value_parser, Args, Parser, Subcommand, ValueEnum};
+use common::{attributes::XattrNamespaces, logging::LogArgs};
+use std::{
+    convert::Infallible,
+    fmt::{self, Display},
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use ubyte::ByteUnit;
 
+/// Command line flags take precedence over the selected profile.
 #[derive(Parser)]
 #[command(version)]
 pub struct Cli {
-    #[arg(short = 'H', long, default_value = "127.0.0.1")]
-    pub address: String,
+    /// Profiles file [default: ~/.config/grpc-file-transfer/config.toml]
+    #[arg(long, env = "FT_CLIENT_CONFIG")]
+    pub config_file: Option<PathBuf>,
+    /// Profile to use from the profiles file
+    #[arg(short = 'P', long, env = "FT_PROFILE")]
+    pub profile: Option<String>,
+    /// Host name, IP address or unix:PATH of a Unix domain socket [default: 127.0.0.1]
+    #[arg(short = 'H', long)]
+    pub address: Option<String>,
     #[arg(short, long)]
-    pub port: u16,
+    pub port: Option<u16>,
     #[command(subcommand)]
     pub command: Commands,
-    #[arg(short, long, default_value = "info")]
-    pub verbose: Level,
-    #[arg(
-        long,
-        required = true,
-        default_value_if("insecure", ArgPredicate::IsPresent, None)
-    )]
+    #[command(flatten)]
+    pub log: LogArgs,
+    #[arg(long)]
     pub cert: Option<PathBuf>,
-    #[arg(
-        long,
-        required = true,
-        default_value_if("insecure", ArgPredicate::IsPresent, None)
-    )]
+    #[arg(long)]
     pub key: Option<PathBuf>,
-    #[arg(
-        long,
-        required = true,
-        default_value_if("insecure", ArgPredicate::IsPresent, None)
-    )]
+    #[arg(long)]
     pub ca_cert: Option<PathBuf>,
     #[arg(short, long, conflicts_with_all = ["key", "cert", "ca_cert"])]
     pub insecure: bool,
+    /// Don't show progress of transfers
+    #[arg(short, long)]
+    pub quiet: bool,
+    /// Bearer token sent with every request
+    #[arg(long, env = "FT_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+    #[command(flatten)]
+    pub retry: RetryArgs,
+    #[command(flatten)]
+    pub timeouts: TimeoutArgs,
+}
+
+/// Limits on how long connecting, calls and streams may take.
+#[derive(Args)]
+pub struct TimeoutArgs {
+    /// Give up connecting to the server after this long
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    pub connect_timeout: Duration,
+    /// Deadline of a call until its response starts, e.g. 30s. Uploads are
+    /// limited by the idle timeout instead
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub request_timeout: Option<Duration>,
+    /// Abort streams without any message for this long, 0s disables
+    #[arg(long, default_value = "1m", value_parser = humantime::parse_duration)]
+    pub idle_timeout: Duration,
+    /// Interval of HTTP/2 keepalive pings, disabled by default
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub keepalive_interval: Option<Duration>,
+    /// Close connection if keepalive ping isn't answered in time [default: 20s]
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub keepalive_timeout: Option<Duration>,
+}
+
+/// Retrying of requests failed by transient errors, e.g. server restart.
+/// Uploads aren't retried, downloads resume where they stopped.
+#[derive(Args)]
+pub struct RetryArgs {
+    /// Number of retries of a failed request, 0 disables retrying
+    #[arg(long, default_value_t = 3)]
+    pub retries: u32,
+    /// Delay before the first retry, doubled with every next one
+    #[arg(long, default_value = "200ms", value_parser = humantime::parse_duration)]
+    pub retry_backoff: Duration,
+    /// Maximum delay between retries
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    pub retry_max_backoff: Duration,
+    /// Fraction of a delay randomly taken off it, from 0 to 1
+    #[arg(long, default_value_t = 0.2, value_parser = parse_jitter)]
+    pub retry_jitter: f64,
+}
+
+/// File on the server addressed as `share:path`, or just `path` for the
+/// default share.
+#[derive(Clone, Debug, Default)]
+pub struct RemotePath {
+    pub share: String,
+    pub path: String,
+}
+
+impl FromStr for RemotePath {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (share, path) = s.split_once(':').unwrap_or(("", s));
+
+        Ok(Self {
+            share: share.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+impl RemotePath {
+    /// Whether the path is a shell pattern to be matched against listed files.
+    pub fn is_pattern(&self) -> bool {
+        self.path.contains(['*', '?', '['])
+    }
+}
+
+impl Display for RemotePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.share.is_empty() {
+            write!(f, "{}", self.path)
+        } else {
+            write!(f, "{}:{}", self.share, self.path)
+        }
+    }
+}
+
+/// Filters and ordering applied by the server to listed files.
+#[derive(Args, Clone, Debug)]
+pub struct ListFilter {
+    /// Only files with names matching shell pattern, e.g. '*.log'
+    #[arg(short, long)]
+    pub glob: Option<String>,
+    /// Only files with names matching regular expression
+    #[arg(short, long)]
+    pub regex: Option<String>,
+    /// Only files of at least this size, e.g. 10MiB
+    #[arg(long, value_parser = parse_size)]
+    pub min_size: Option<u64>,
+    /// Only files of at most this size, e.g. 10MiB
+    #[arg(long, value_parser = parse_size)]
+    pub max_size: Option<u64>,
+    /// Only files modified since RFC 3339 time or duration ago, e.g. 2h
+    #[arg(long, value_parser = parse_since)]
+    pub modified_since: Option<SystemTime>,
+    #[arg(long, value_enum)]
+    pub sort: Option<SortKey>,
+    /// Sort in descending order
+    #[arg(long, requires = "sort")]
+    pub reverse: bool,
+    /// Maximum number of listed files
+    #[arg(short, long, conflicts_with = "page_size")]
+    pub limit: Option<u32>,
+    /// Fetch files in pages of this size, sorted by name unless --sort is set
+    #[arg(long)]
+    pub page_size: Option<u32>,
+    /// Continue listing from page token of interrupted listing
+    #[arg(long, requires = "page_size")]
+    pub page_token: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    s.parse::<ByteUnit>()
+        .map(|size| size.as_u64())
+        .map_err(|_| format!("expected size like 512, 64kB or 10MiB, got {s:?}"))
+}
+
+fn parse_jitter(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(jitter) if (0.0..=1.0).contains(&jitter) => Ok(jitter),
+        _ => Err(format!("expected number from 0 to 1, got {s:?}")),
+    }
+}
+
+fn parse_since(s: &str) -> Result<SystemTime, String> {
+    let time = match humantime::parse_duration(s) {
+        Ok(duration) => SystemTime::now()
+            .checked_sub(duration)
+            .unwrap_or(UNIX_EPOCH),
+        Err(_) => humantime::parse_rfc3339_weak(s)
+            .map_err(|_| format!("expected RFC 3339 time or duration, got {s:?}"))?,
+    };
+
+    Ok(time)
+}
+
+/// Format of command output, `json`, `csv` and `tsv` are meant for scripts.
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+    Csv,
+    Tsv,
+}
+
+/// File metadata kept across transfers.
+#[derive(Args, Clone, Debug)]
+pub struct PreserveArgs {
+    /// Keep modification time and permissions of transferred files
+    #[arg(long)]
+    pub preserve: bool,
+    /// Keep extended attributes of transferred files, if the server allows it
+    #[arg(long)]
+    pub xattrs: bool,
+    /// Namespaces of kept extended attributes, e.g. system for POSIX ACLs
+    #[arg(
+        long,
+        default_value = "user",
+        value_delimiter = ',',
+        requires = "xattrs"
+    )]
+    pub xattr_namespaces: Vec<String>,
+}
+
+impl PreserveArgs {
+    /// Namespaces of kept extended attributes, `None` unless `--xattrs` is set.
+    pub fn xattr_namespaces(&self) -> Option<XattrNamespaces> {
+        self.xattrs
+            .then(|| XattrNamespaces::new(&self.xattr_namespaces))
+    }
+}
+
+/// Archive format built or extracted by the server.
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum ArchiveFormat {
+    #[default]
+    Tar,
+    /// Tar compressed with zstd
+    TarZst,
+    Zip,
+}
+
+/// Which side `sync` makes match the other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SyncDirection {
+    /// Make the server match the local directory
+    #[default]
+    Push,
+    /// Make the local directory match the server
+    Pull,
+    /// Copy files missing on either side, and resolve files which differ
+    /// by --conflict
+    TwoWay,
+}
+
+/// Which version of a file wins when it differs on both sides of a
+/// two-way sync.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ConflictPolicy {
+    /// The one modified last, files modified at the same time are skipped
+    #[default]
+    Newer,
+    Local,
+    Remote,
+    /// Leave both as they are
+    Skip,
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
     Download {
+        /// File name or shell pattern, e.g. 'docs:*.pdf', can be repeated
+        #[arg(short, long = "file", value_name = "[SHARE:]FILE", required = true)]
+        files: Vec<RemotePath>,
+        /// [default: profile directory or current directory]
         #[arg(short, long)]
-        file: String,
-        #[arg(short, long)]
-        directory: PathBuf,
+        directory: Option<PathBuf>,
+        /// Number of files downloaded concurrently
+        #[arg(short, long, default_value_t = 4, value_parser = value_parser!(u16).range(1..))]
+        jobs: u16,
+        /// Write the single file here instead of into the directory,
+        /// - for standard output
+        #[arg(value_name = "LOCAL", conflicts_with = "directory")]
+        local: Option<PathBuf>,
+        #[command(flatten)]
+        preserve: PreserveArgs,
+        /// Only fetch blocks which differ from the existing local file
+        #[arg(long)]
+        delta: bool,
     },
     Upload {
+        #[arg(short, long, value_name = "[SHARE:]FILE")]
+        file: RemotePath,
+        /// [default: profile directory or current directory]
+        #[arg(short, long)]
+        directory: Option<PathBuf>,
+        /// Read the file from here instead of from the directory,
+        /// - for standard input
+        #[arg(value_name = "LOCAL", conflicts_with = "directory")]
+        local: Option<PathBuf>,
+        #[command(flatten)]
+        preserve: PreserveArgs,
+        /// Only send blocks which differ from the file on the server
+        #[arg(long)]
+        delta: bool,
+    },
+    List {
+        /// [default: default share]
+        #[arg(short, long)]
+        share: Option<String>,
+        #[command(flatten)]
+        filter: ListFilter,
+        #[arg(short, long, value_enum, default_value_t)]
+        output: OutputFormat,
+        /// Print sizes in bytes in table output
+        #[arg(long)]
+        bytes: bool,
+    },
+    Shares,
+    /// Show operations allowed on a share
+    Capabilities {
+        /// [default: default share]
+        #[arg(short, long)]
+        share: Option<String>,
+    },
+    /// Download a directory as an archive built by the server
+    DownloadArchive {
+        /// Directory in a share, e.g. 'docs:reports', or 'docs:' for the
+        /// whole share [default: default share]
+        #[arg(short, long, value_name = "[SHARE:]DIRECTORY")]
+        remote: Option<RemotePath>,
+        #[arg(long, value_enum, default_value_t)]
+        format: ArchiveFormat,
+        /// Archive file to write, - for standard output
+        #[arg(value_name = "LOCAL")]
+        local: PathBuf,
+    },
+    /// Upload an archive which the server extracts into a directory
+    UploadArchive {
+        /// Directory in a share, created if missing [default: default share]
+        #[arg(short, long, value_name = "[SHARE:]DIRECTORY")]
+        remote: Option<RemotePath>,
+        #[arg(long, value_enum, default_value_t)]
+        format: ArchiveFormat,
+        /// Archive file to read, - for standard input
+        #[arg(value_name = "LOCAL")]
+        local: PathBuf,
+    },
+    /// Make a local directory tree and a directory on the server match
+    Sync {
+        /// Directory in a share, e.g. 'docs:reports', or 'docs:' for the
+        /// whole share [default: default share]
+        #[arg(short, long, value_name = "[SHARE:]DIRECTORY")]
+        remote: Option<RemotePath>,
+        /// [default: profile directory or current directory]
         #[arg(short, long)]
-        file: String,
+        directory: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t)]
+        direction: SyncDirection,
+        #[arg(long, value_enum, default_value_t)]
+        conflict: ConflictPolicy,
+        /// Delete files missing on the other side, only with push or pull
+        #[arg(long)]
+        delete: bool,
+        /// Compare files by SHA-256 instead of modification time
         #[arg(short, long)]
-        directory: PathBuf,
+        checksum: bool,
+        /// Only print what would be done
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Only send blocks which differ from existing files
+        #[arg(long)]
+        delta: bool,
     },
-    List,
 }
@@ -0,0 +1,157 @@
+use crate::cli::Cli;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// Profiles file, by default `~/.config/grpc-file-transfer/config.toml`.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    default_profile: Option<String>,
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+/// Named set of connection settings and defaults.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    address: Option<String>,
+    port: Option<u16>,
+    insecure: Option<bool>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    ca_cert: Option<PathBuf>,
+    token: Option<String>,
+    directory: Option<PathBuf>,
+}
+
+/// Effective client configuration, merged from the selected profile and
+/// command line flags.
+pub struct Config {
+    pub address: String,
+    /// Not used with `unix:` addresses
+    pub port: Option<u16>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub ca_cert: Option<PathBuf>,
+    pub token: Option<String>,
+    pub directory: PathBuf,
+}
+
+impl Config {
+    const APP_NAME: &str = "grpc-file-transfer";
+    const CONFIG_FILE_NAME: &str = "config.toml";
+    const DEFAULT_ADDRESS: &str = "127.0.0.1";
+
+    pub fn load(args: &Cli) -> Result<Self> {
+        let profile = Profile::select(args)?;
+
+        Self::merge(args, profile)
+    }
+
+    fn default_config_file() -> Option<PathBuf> {
+        let mut path = dirs::config_dir()?;
+        path.push(Self::APP_NAME);
+        path.push(Self::CONFIG_FILE_NAME);
+
+        Some(path)
+    }
+
+    fn merge(args: &Cli, profile: Profile) -> Result<Self> {
+        let address = args
+            .address
+            .clone()
+            .or(profile.address)
+            .unwrap_or_else(|| Self::DEFAULT_ADDRESS.to_string());
+        // Unix domain sockets are local and always used without TLS
+        let unix = address.starts_with("unix:");
+
+        let port = args.port.or(profile.port);
+        if port.is_none() && !unix {
+            return Err(missing("port", "--port"));
+        }
+
+        let tls_args = args.cert.is_some() || args.key.is_some() || args.ca_cert.is_some();
+        let insecure = args.insecure || (!tls_args && profile.insecure.unwrap_or(false));
+
+        let (cert, key, ca_cert) = if insecure || unix {
+            (None, None, None)
+        } else {
+            let cert = args
+                .cert
+                .clone()
+                .or(profile.cert)
+                .ok_or_else(|| missing("cert", "--cert"))?;
+            let key = args
+                .key
+                .clone()
+                .or(profile.key)
+                .ok_or_else(|| missing("key", "--key"))?;
+            let ca_cert = args
+                .ca_cert
+                .clone()
+                .or(profile.ca_cert)
+                .ok_or_else(|| missing("ca_cert", "--ca-cert"))?;
+            (Some(cert), Some(key), Some(ca_cert))
+        };
+
+        Ok(Self {
+            address,
+            port,
+            cert,
+            key,
+            ca_cert,
+            token: args.token.clone().or(profile.token),
+            directory: profile.directory.unwrap_or_else(|| PathBuf::from(".")),
+        })
+    }
+}
+
+impl Profile {
+    fn select(args: &Cli) -> Result<Self> {
+        let path = match &args.config_file {
+            Some(path) => Some(path.clone()),
+            None => Config::default_config_file(),
+        };
+
+        let config_file = match path {
+            Some(path) if path.exists() => ConfigFile::read(&path)?,
+            Some(path) if args.config_file.is_some() => {
+                bail!("profiles file {path:?} does not exist")
+            }
+            _ => ConfigFile::default(),
+        };
+
+        let name = match args
+            .profile
+            .as_ref()
+            .or(config_file.default_profile.as_ref())
+        {
+            Some(name) => name,
+            None => return Ok(Profile::default()),
+        };
+
+        config_file
+            .profiles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("profile `{name}` not found"))
+    }
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read profiles file {path:?}"))?;
+
+        toml::from_str(&content).with_context(|| format!("invalid profiles file {path:?}"))
+    }
+}
+
+fn missing(key: &str, flag: &str) -> anyhow::Error {
+    anyhow!("missing `{key}`: set {flag} or `{key}` in the profile")
+}
@@ -1,27 +1,101 @@
-use crate::output_print::FilesOutputPrint;
-use anyhow::Result;
+use crate::{
+    cli::{
+        ArchiveFormat, ListFilter, OutputFormat, PreserveArgs, RemotePath, SortKey, SyncDirection,
+    },
+    output_print::{
+        CapabilitiesOutputPrint, FilesPagePrinter, SharesOutputPrint, SyncOutputPrint,
+        TransfersOutputPrint,
+    },
+    progress::{Progress, Transfer},
+    retry::{is_transient, retry_after, RetryPolicy},
+    sync::{self, SyncAction, SyncFile, SyncOptions},
+    timeouts::{next_message, response, send_message, Timeouts},
+};
+use anyhow::{anyhow, Result};
+use common::{
+    attributes,
+    delta::{self, DeltaDecoder, DeltaOp},
+    sparse::{self, HoleWrite, Piece, SparseReader},
+    temp_file::TempFile,
+};
+use proto::api::{
+    self, download_file_response, file_service_client::FileServiceClient, upload_archive_request,
+    upload_file_request, DownloadArchiveRequest, DownloadFileHeader, DownloadFileRequest,
+    GetCapabilitiesRequest, ListFilesRequest, ListFilesResponse, ListSharesRequest, SortBy,
+    UploadArchiveHeader, UploadArchiveRequest, UploadFileHeader, UploadFileRequest,
+};
 use proto::api::{
-    file_service_client::FileServiceClient, upload_file_request, DownloadFileRequest,
-    ListFilesRequest, UploadFileRequest,
+    download_delta_request, download_delta_response, upload_delta_request, BlockSignature,
+    BlockSignatures, CopyBlocks, DownloadDeltaHeader, DownloadDeltaRequest, GetSignaturesRequest,
+    UploadDeltaHeader, UploadDeltaRequest,
+};
+use proto::api::{DeleteFileRequest, ListTreeRequest};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::Metadata,
+    net::IpAddr,
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
 };
-use std::{net::IpAddr, path::PathBuf};
 use tokio::{
     fs,
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UnixStream,
     sync::mpsc,
+    task::JoinSet,
+    time::{sleep, timeout},
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    transport::{channel::Channel, Certificate, ClientTlsConfig, Endpoint, Identity, Uri},
+    Code, Request, Status,
 };
-use tokio_stream::{wrappers::ReceiverStream, StreamExt};
-use tonic::transport::{channel::Channel, Certificate, ClientTlsConfig, Identity};
-use tracing::{debug, error, instrument, Instrument};
+use tower::service_fn;
+use tracing::{debug, error, info, instrument, warn, Instrument};
 
 #[derive(Clone)]
 pub struct FileClient<T> {
     client: FileServiceClient<T>,
+    token: Option<MetadataValue<Ascii>>,
+    progress: Progress,
+    endpoint: Endpoint,
+    /// Set when connected over a Unix domain socket
+    socket_path: Option<PathBuf>,
+    retry: RetryPolicy,
+    timeouts: Timeouts,
 }
 
 impl<T> FileClient<T> {
     const CHANNEL_SIZE: usize = 10;
     const CHUNK_SIZE_BYTES: u64 = 1024 * 1024; // 1 MB
+    const SIGNATURE_BATCH_SIZE: usize = 4096;
+
+    /// Request of a unary or server streaming call, whose response has to
+    /// start within the request timeout.
+    fn request<M>(&self, message: M) -> Request<M> {
+        let mut request = self.stream_request(message);
+
+        if let Some(deadline) = self.timeouts.request {
+            request.set_timeout(deadline);
+        }
+
+        request
+    }
+
+    /// Request of a call streaming from the client. Its response starts only
+    /// once the whole stream is sent, so just the idle timeout applies.
+    fn stream_request<M>(&self, message: M) -> Request<M> {
+        let mut request = Request::new(message);
+
+        if let Some(token) = &self.token {
+            request
+                .metadata_mut()
+                .insert("authorization", token.clone());
+        }
+
+        request
+    }
 }
 
 fn create_uri(host: &str, port: u16, tls: bool) -> String {
@@ -37,6 +111,157 @@ fn create_uri(host: &str, port: u16, tls: bool) -> String {
     format!("{scheme}://{host}:{port}")
 }
 
+/// Whether local path stands for standard input or output.
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Local counterpart of remote file, named after its last path component.
+fn local_path(directory: &Path, file: &RemotePath) -> Result<PathBuf> {
+    let file_name = Path::new(&file.path)
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid file name: {:?}", file.path))?;
+
+    Ok(directory.join(file_name))
+}
+
+/// Whether headers of two download attempts describe the same file.
+fn same_version(first: &DownloadFileHeader, header: &DownloadFileHeader) -> bool {
+    let modified = |header: &DownloadFileHeader| {
+        header
+            .attributes
+            .as_ref()
+            .map(|attributes| attributes.modified_ns)
+    };
+
+    first.size == header.size && modified(first) == modified(header)
+}
+
+fn list_files_request(share: String, filter: &ListFilter) -> ListFilesRequest {
+    let mut request = ListFilesRequest {
+        share,
+        glob: filter.glob.clone().unwrap_or_default(),
+        regex: filter.regex.clone().unwrap_or_default(),
+        min_size: filter.min_size,
+        max_size: filter.max_size,
+        modified_since: filter.modified_since.map(|time| {
+            time.duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs())
+        }),
+        reverse: filter.reverse,
+        limit: filter.limit.unwrap_or(0),
+        page_size: filter.page_size.unwrap_or(0),
+        page_token: filter.page_token.clone().unwrap_or_default(),
+        ..Default::default()
+    };
+
+    request.set_sort_by(match filter.sort {
+        None => SortBy::None,
+        Some(SortKey::Name) => SortBy::Name,
+        Some(SortKey::Size) => SortBy::Size,
+        Some(SortKey::Modified) => SortBy::Modified,
+    });
+
+    request
+}
+
+/// Lazily fetched pages of `ListFiles`, a page is requested only when the
+/// previous one has been consumed.
+struct FilePages<'a> {
+    client: &'a mut FileClient<Channel>,
+    request: ListFilesRequest,
+    done: bool,
+}
+
+impl<'a> FilePages<'a> {
+    fn new(client: &'a mut FileClient<Channel>, request: ListFilesRequest) -> Self {
+        FilePages {
+            client,
+            request,
+            done: false,
+        }
+    }
+
+    /// Fetches next page, `None` after the last one.
+    async fn next_page(&mut self) -> Result<Option<Vec<ListFilesResponse>>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let mut attempt = 0;
+        loop {
+            match self.fetch_page().await {
+                Err(err) if self.client.should_retry(&err, &mut attempt).await => continue,
+                result => return result.map(Some),
+            }
+        }
+    }
+
+    async fn fetch_page(&mut self) -> Result<Vec<ListFilesResponse>> {
+        let idle_timeout = self.client.timeouts.idle;
+        let call = self
+            .client
+            .client
+            .list_files(self.client.request(self.request.clone()));
+        let response = response(call, idle_timeout).await?;
+
+        let mut files_stream = response.into_inner();
+        let mut files = Vec::new();
+        let mut next_page_token = String::new();
+
+        while let Some(item) = next_message(&mut files_stream, self.client.timeouts.idle).await? {
+            let file = item?;
+            if !file.next_page_token.is_empty() {
+                next_page_token = file.next_page_token.clone();
+            }
+            files.push(file);
+        }
+
+        if next_page_token.is_empty() {
+            self.done = true;
+        } else {
+            debug!("Next page token {}", next_page_token);
+            self.request.page_token = next_page_token;
+        }
+
+        Ok(files)
+    }
+}
+
+async fn connect(
+    endpoint: &Endpoint,
+    socket_path: Option<&Path>,
+    connect_timeout: Option<Duration>,
+) -> Result<Channel> {
+    let channel = if let Some(socket_path) = socket_path {
+        debug!("Connecting to unix:{}", socket_path.display());
+
+        // Endpoint applies connect timeout only to its own TCP connector
+        let socket_path = socket_path.to_path_buf();
+        endpoint
+            .connect_with_connector(service_fn(move |_: Uri| {
+                let connect = UnixStream::connect(socket_path.clone());
+                async move {
+                    match connect_timeout {
+                        Some(connect_timeout) => {
+                            timeout(connect_timeout, connect).await.unwrap_or_else(|_| {
+                                Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))
+                            })
+                        }
+                        None => connect.await,
+                    }
+                }
+            }))
+            .await?
+    } else {
+        debug!("Connecting to {}", endpoint.uri());
+
+        endpoint.connect().await?
+    };
+
+    Ok(channel)
+}
+
 fn create_tls_config(
     ca_cert_pem: &str,
     domain_name: &str,
@@ -55,91 +280,532 @@ fn create_tls_config(
 }
 
 impl FileClient<Channel> {
-    #[instrument]
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(token))]
     pub async fn new(
         address: &str,
-        port: u16,
+        port: Option<u16>,
         ca_cert_pem: Option<&str>,
         cert: Option<&str>,
         key: Option<&str>,
+        token: Option<&str>,
+        retry: RetryPolicy,
+        timeouts: Timeouts,
     ) -> Result<Self> {
-        let enable_tls = ca_cert_pem.is_some() && cert.is_some() && key.is_some();
-        let dst = create_uri(address, port, enable_tls);
+        let (endpoint, socket_path) = if let Some(socket_path) = address.strip_prefix("unix:") {
+            // URI is required by the endpoint but ignored by the connector
+            (
+                Endpoint::from_static("http://[::]:50051"),
+                Some(PathBuf::from(socket_path)),
+            )
+        } else {
+            let port = port.ok_or_else(|| anyhow!("Port is required for TCP address"))?;
+            let enable_tls = ca_cert_pem.is_some() && cert.is_some() && key.is_some();
+            let dst = create_uri(address, port, enable_tls);
 
-        debug!("Connecting to {}", dst);
+            let mut endpoint = Channel::from_shared(dst)?;
 
-        let mut endpoint = Channel::from_shared(dst)?;
+            if enable_tls {
+                let tls_config =
+                    create_tls_config(ca_cert_pem.unwrap(), address, cert.unwrap(), key.unwrap())?;
+                endpoint = endpoint.tls_config(tls_config)?;
+            }
 
-        if enable_tls {
-            let tls_config =
-                create_tls_config(ca_cert_pem.unwrap(), address, cert.unwrap(), key.unwrap())?;
-            endpoint = endpoint.tls_config(tls_config)?;
-        }
+            (endpoint, None)
+        };
+        let endpoint = timeouts.apply(endpoint);
 
-        let channel = endpoint.connect().await?;
+        let mut attempt = 0;
+        let channel = loop {
+            match connect(&endpoint, socket_path.as_deref(), timeouts.connect).await {
+                Ok(channel) => break channel,
+                Err(err) => {
+                    attempt += 1;
+                    let Some(delay) = retry.delay(attempt) else {
+                        return Err(err);
+                    };
+                    warn!(
+                        "{err:#}, retrying in {delay:?} ({attempt}/{})",
+                        retry.retries
+                    );
+                    sleep(delay).await;
+                }
+            }
+        };
 
         let client = FileServiceClient::new(channel);
+        let token = token
+            .map(|token| format!("Bearer {token}").parse())
+            .transpose()?;
 
         debug!("Connected");
-        Ok(Self { client })
+        Ok(Self {
+            client,
+            token,
+            progress: Progress::default(),
+            endpoint,
+            socket_path,
+            retry,
+            timeouts,
+        })
+    }
+
+    /// Waits before another attempt of a failed request if the error is
+    /// transient and retries are left, reconnecting in the meantime.
+    async fn should_retry(&mut self, err: &anyhow::Error, attempt: &mut u32) -> bool {
+        if !is_transient(err) {
+            return false;
+        }
+
+        *attempt += 1;
+        let Some(mut delay) = self.retry.delay(*attempt) else {
+            return false;
+        };
+
+        let retry_after = retry_after(err);
+        if let Some(retry_after) = retry_after {
+            delay = delay.max(retry_after);
+        }
+
+        warn!(
+            "{err:#}, retrying in {delay:?} ({attempt}/{})",
+            self.retry.retries
+        );
+        sleep(delay).await;
+
+        // Rate limited requests fail on a healthy connection
+        if retry_after.is_none() {
+            match connect(
+                &self.endpoint,
+                self.socket_path.as_deref(),
+                self.timeouts.connect,
+            )
+            .await
+            {
+                Ok(channel) => self.client = FileServiceClient::new(channel),
+                Err(err) => debug!("Reconnect failed: {err:#}"),
+            }
+        }
+
+        true
+    }
+
+    /// Reports progress of downloads and uploads, which are silent otherwise.
+    pub fn with_progress(self, progress: Progress) -> Self {
+        Self { progress, ..self }
     }
 
     #[instrument(skip(self))]
-    pub async fn list_files(&mut self) -> Result<()> {
-        let mut files = Vec::new();
+    pub async fn list_files(
+        &mut self,
+        share: String,
+        filter: &ListFilter,
+        output: OutputFormat,
+        bytes: bool,
+    ) -> Result<()> {
+        let mut printer = FilesPagePrinter::new(output, bytes);
+        let mut pages = FilePages::new(self, list_files_request(share, filter));
 
-        let response = self.client.list_files(ListFilesRequest {}).await?;
+        let result = loop {
+            match pages.next_page().await {
+                Ok(Some(page)) => printer.print_page(page)?,
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
 
-        let mut files_stream = response.into_inner();
+        // Pages listed so far are printed, the rest can be listed with the token
+        if let Err(err) = result {
+            if printer.is_empty() || pages.request.page_token.is_empty() {
+                return Err(err);
+            }
+            printer.finish()?;
+            return Err(err.context(format!(
+                "Listing interrupted, resume with --page-token {}",
+                pages.request.page_token
+            )));
+        }
+
+        printer.finish()?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_shares(&mut self) -> Result<()> {
+        let mut attempt = 0;
+        let response = loop {
+            let call = self.client.list_shares(self.request(ListSharesRequest {}));
+            match response(call, self.timeouts.idle).await {
+                Err(status) => {
+                    let err = status.into();
+                    if !self.should_retry(&err, &mut attempt).await {
+                        return Err(err);
+                    }
+                }
+                Ok(response) => break response,
+            }
+        };
+
+        println!("{}", SharesOutputPrint::from(response.into_inner().shares));
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_capabilities(&mut self, share: String) -> Result<()> {
+        let mut attempt = 0;
+        let response = loop {
+            let call = self
+                .client
+                .get_capabilities(self.request(GetCapabilitiesRequest {
+                    share: share.clone(),
+                }));
+            match response(call, self.timeouts.idle).await {
+                Err(status) => {
+                    let err = status.into();
+                    if !self.should_retry(&err, &mut attempt).await {
+                        return Err(err);
+                    }
+                }
+                Ok(response) => break response,
+            }
+        };
+
+        println!("{}", CapabilitiesOutputPrint::from(response.into_inner()));
+
+        Ok(())
+    }
+
+    /// Expands shell patterns to matching files listed by the server, other
+    /// names are kept as they are.
+    async fn resolve_files(&mut self, files: Vec<RemotePath>) -> Result<Vec<RemotePath>> {
+        let mut resolved = Vec::new();
+        let mut seen = HashSet::new();
+
+        for file in files {
+            let matches = if file.is_pattern() {
+                let request = ListFilesRequest {
+                    share: file.share.clone(),
+                    glob: file.path.clone(),
+                    ..Default::default()
+                };
+                let mut pages = FilePages::new(self, request);
+                let mut matches = Vec::new();
 
-        while let Some(item) = files_stream.next().await {
-            files.push(item?);
+                while let Some(page) = pages.next_page().await? {
+                    matches.extend(page.into_iter().map(|listed| RemotePath {
+                        share: file.share.clone(),
+                        path: listed.name,
+                    }));
+                }
+
+                if matches.is_empty() {
+                    return Err(anyhow!("No files match {file}"));
+                }
+                matches
+            } else {
+                vec![file]
+            };
+
+            for file in matches {
+                if seen.insert(file.to_string()) {
+                    resolved.push(file);
+                }
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    /// Downloads files and patterns, at most `jobs` at once, and prints
+    /// result of every file if there is more than one. A single file can
+    /// be written to `local` instead of `directory`.
+    #[instrument(skip(self))]
+    pub async fn download_files(
+        &mut self,
+        files: Vec<RemotePath>,
+        directory: PathBuf,
+        local: Option<PathBuf>,
+        jobs: usize,
+        preserve: PreserveArgs,
+        delta: bool,
+    ) -> Result<()> {
+        let mut files = self.resolve_files(files).await?;
+        if files.len() == 1 {
+            let file = files.remove(0);
+            let local = match local {
+                Some(local) => local,
+                None => local_path(&directory, &file)?,
+            };
+            return self.download_file(file, local, &preserve, delta).await;
+        }
+
+        if let Some(local) = local {
+            return Err(anyhow!(
+                "Only a single file can be written to {local:?}, {} match",
+                files.len()
+            ));
         }
 
-        println!("{}", FilesOutputPrint::from(files));
+        let total = files.len();
+        let mut results = Vec::with_capacity(total);
+        let mut downloads = JoinSet::new();
+
+        for (index, file) in files.into_iter().enumerate() {
+            if downloads.len() >= jobs {
+                if let Some(result) = downloads.join_next().await {
+                    results.push(result?);
+                }
+            }
+
+            let mut client = self.clone();
+            let directory = directory.clone();
+            let preserve = preserve.clone();
+            downloads.spawn(async move {
+                let name = file.to_string();
+                let result = match local_path(&directory, &file) {
+                    Ok(local) => client.download_file(file, local, &preserve, delta).await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = &result {
+                    error!(file = name, "{err:#}");
+                }
+                (index, name, result.map_err(|err| format!("{err:#}")))
+            });
+        }
+
+        while let Some(result) = downloads.join_next().await {
+            results.push(result?);
+        }
+
+        results.sort_by_key(|(index, _, _)| *index);
+        let failed = results
+            .iter()
+            .filter(|(_, _, result)| result.is_err())
+            .count();
+
+        let transfers = results
+            .into_iter()
+            .map(|(_, name, result)| (name, result))
+            .collect::<Vec<_>>();
+        println!("{}", TransfersOutputPrint::from(transfers));
+
+        if failed > 0 {
+            return Err(anyhow!("{failed} of {total} downloads failed"));
+        }
 
         Ok(())
     }
 
+    /// Downloads file to `local` path, `-` being standard output, and keeps
+    /// its metadata on the server as requested by `preserve`. With `delta`
+    /// an existing file at `local` is only patched. Files are written next
+    /// to `local`, which they only replace once complete.
     #[instrument(skip(self))]
-    pub async fn download_file(&mut self, file: String, directory: PathBuf) -> Result<()> {
-        let response = self
-            .client
-            .download_file(DownloadFileRequest {
-                name: file.to_string(),
-            })
-            .await?;
+    pub async fn download_file(
+        &mut self,
+        file: RemotePath,
+        local: PathBuf,
+        preserve: &PreserveArgs,
+        delta: bool,
+    ) -> Result<()> {
+        let xattr_namespaces = preserve.xattr_namespaces();
 
-        let mut file_stream = response.into_inner();
-        let mut file_path = directory;
-        file_path.push(file);
+        if is_stdio(&local) {
+            let mut stdout = io::stdout();
+            self.download_to(file, false, &mut stdout).await?;
+            stdout.flush().await?;
+        } else {
+            let existing = matches!(fs::metadata(&local).await, Ok(metadata) if metadata.is_file());
+            let (temp_file, header) = if delta && existing {
+                self.download_delta(file, &local, xattr_namespaces.is_some())
+                    .await?
+            } else {
+                // Retries resume writing the same temporary file
+                let (temp_file, mut output) = TempFile::create(&local).await?;
+                let header = self
+                    .download_to(file, xattr_namespaces.is_some(), &mut output)
+                    .await?
+                    .unwrap_or_default();
+                sparse::finish_holes(&mut output).await?;
+                output.sync_all().await?;
+                (temp_file, header)
+            };
+
+            // Before permissions, which could make the file read-only
+            if let Some(namespaces) = &xattr_namespaces {
+                attributes::apply_xattrs(temp_file.path(), header.xattrs, namespaces).await?;
+            }
+            if let (true, Some(file_attributes)) = (preserve.preserve, header.attributes) {
+                attributes::apply(temp_file.path(), &file_attributes).await?;
+            }
+            temp_file.persist().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns header of the downloaded file sent by the server, with
+    /// extended attributes if `xattrs` is set.
+    async fn download_to<W>(
+        &mut self,
+        file: RemotePath,
+        xattrs: bool,
+        output: &mut W,
+    ) -> Result<Option<DownloadFileHeader>>
+    where
+        W: HoleWrite,
+    {
+        let name = file.to_string();
+        let mut request = DownloadFileRequest {
+            name: file.path,
+            share: file.share,
+            offset: 0,
+            xattrs,
+        };
 
-        let mut file = fs::File::create(&file_path).await?;
+        let mut transfer = None;
+        let mut file_header = None;
+        let mut attempt = 0;
 
-        while let Some(item) = file_stream.next().await {
-            file.write_all(&item?.chunk).await?
+        loop {
+            let offset = request.offset;
+            match self
+                .download_chunks(&mut request, &name, output, &mut transfer, &mut file_header)
+                .await
+            {
+                Ok(()) => break,
+                Err(err) => {
+                    // Only failures without any progress in between add up
+                    if request.offset > offset {
+                        attempt = 0;
+                    }
+                    if !self.should_retry(&err, &mut attempt).await {
+                        return Err(err);
+                    }
+                    debug!("Resuming download of {} at {}", name, request.offset);
+                }
+            }
+        }
+
+        if let Some(transfer) = transfer {
+            transfer.finish();
         }
 
-        file.sync_all().await?;
+        Ok(file_header)
+    }
+
+    /// Writes file from `request.offset` on, which follows written data.
+    async fn download_chunks<W>(
+        &mut self,
+        request: &mut DownloadFileRequest,
+        name: &str,
+        output: &mut W,
+        transfer: &mut Option<Transfer>,
+        file_header: &mut Option<DownloadFileHeader>,
+    ) -> Result<()>
+    where
+        W: HoleWrite,
+    {
+        let call = self.client.download_file(self.request(request.clone()));
+        let response = response(call, self.timeouts.idle).await?;
+
+        let mut file_stream = response.into_inner();
+
+        while let Some(item) = next_message(&mut file_stream, self.timeouts.idle).await? {
+            match item?.r#type {
+                Some(download_file_response::Type::Header(header)) => {
+                    // Resumed downloads must continue the same version of the file
+                    if let Some(first) = file_header {
+                        if !same_version(first, &header) {
+                            return Err(anyhow!(
+                                "{name} changed on the server while it was downloaded"
+                            ));
+                        }
+                    }
+                    // Resumed downloads keep reporting to the same transfer
+                    if transfer.is_none() {
+                        *transfer = Some(self.progress.start(name, Some(header.size)));
+                    }
+                    *file_header = Some(header);
+                }
+                Some(download_file_response::Type::Chunk(chunk)) => {
+                    output.write_all(&chunk).await?;
+                    request.offset += chunk.len() as u64;
+                    transfer
+                        .get_or_insert_with(|| self.progress.start(name, None))
+                        .inc(chunk.len() as u64);
+                }
+                Some(download_file_response::Type::Hole(len)) => {
+                    request.offset = request
+                        .offset
+                        .checked_add(len)
+                        .ok_or_else(|| anyhow!("{name} has a hole beyond the maximum file size"))?;
+                    output.write_hole(len).await?;
+                    transfer
+                        .get_or_insert_with(|| self.progress.start(name, None))
+                        .inc(len);
+                }
+                None => {}
+            }
+        }
 
         Ok(())
     }
 
+    /// Uploads file from `local` path, `-` being standard input, or from
+    /// its namesake in `directory`.
     #[instrument(skip(self))]
-    pub async fn upload_file(&mut self, file: String, directory: PathBuf) -> Result<()> {
+    pub async fn upload_file(
+        &mut self,
+        file: RemotePath,
+        directory: PathBuf,
+        local: Option<PathBuf>,
+        preserve: PreserveArgs,
+        delta: bool,
+    ) -> Result<()> {
+        let file_path = match local {
+            Some(local) => local,
+            None => local_path(&directory, &file)?,
+        };
+
+        if delta && !is_stdio(&file_path) {
+            match self.get_signatures(&file).await? {
+                Some((block_size, signatures)) => {
+                    return self
+                        .upload_delta(file, &file_path, &preserve, block_size, signatures)
+                        .await;
+                }
+                None => debug!(
+                    "No signatures of {} on the server, uploading it whole",
+                    file
+                ),
+            }
+        }
+
         let (tx, rx) = mpsc::channel(Self::CHANNEL_SIZE);
 
         let receiver_stream = ReceiverStream::new(rx);
 
-        let mut file_path = PathBuf::new();
-        file_path.push(&directory);
-        file_path.push(&file);
+        // Holes of sparse files are sent by length instead of as zeros
+        let (input, metadata) = if is_stdio(&file_path) {
+            (None, None)
+        } else {
+            let input = fs::File::open(&file_path).await?;
+            let metadata = input.metadata().await?;
+            (Some(input), Some(metadata))
+        };
+        let file_size = metadata.as_ref().map(|metadata| metadata.len());
+        let header = upload_header(&file, &file_path, metadata.as_ref(), &preserve).await?;
+        let mut transfer = self.progress.start(&file.to_string(), file_size);
+        let idle_timeout = self.timeouts.idle;
 
         let task_handle = tokio::spawn(
             async move {
                 if let Err(err) = tx
                     .send(UploadFileRequest {
-                        r#type: Some(upload_file_request::Type::Name(file)),
+                        r#type: Some(upload_file_request::Type::Header(header)),
                     })
                     .await
                 {
@@ -147,46 +813,671 @@ impl FileClient<Channel> {
                     Err(err)?;
                 }
 
-                let file = fs::File::open(file_path).await?;
-                let mut handle = file.take(Self::CHUNK_SIZE_BYTES);
+                match (input, file_size) {
+                    (Some(input), Some(size)) => {
+                        let reader = SparseReader::new(input, 0, size).await?;
+                        send_pieces(reader, &tx, idle_timeout, &mut transfer, |piece| {
+                            let message = match piece {
+                                Piece::Data(chunk) => upload_file_request::Type::Chunk(chunk),
+                                Piece::Hole(len) => upload_file_request::Type::Hole(len),
+                            };
+                            UploadFileRequest {
+                                r#type: Some(message),
+                            }
+                        })
+                        .await?;
+                    }
+                    _ => {
+                        send_chunks(
+                            Box::new(io::stdin()),
+                            &tx,
+                            idle_timeout,
+                            &mut transfer,
+                            |chunk| UploadFileRequest {
+                                r#type: Some(upload_file_request::Type::Chunk(chunk)),
+                            },
+                        )
+                        .await?;
+                    }
+                }
 
-                loop {
-                    let mut chunk = Vec::with_capacity(Self::CHUNK_SIZE_BYTES as usize);
+                transfer.finish();
 
-                    let n = handle.read_to_end(&mut chunk).await?;
+                Ok::<(), anyhow::Error>(())
+            }
+            .in_current_span(),
+        );
 
-                    if 0 == n {
-                        break;
-                    } else {
-                        handle.set_limit(Self::CHUNK_SIZE_BYTES);
-                    }
+        let request = self.stream_request(receiver_stream);
+        self.client.upload_file(request).await?;
 
-                    let request = UploadFileRequest {
-                        r#type: Some(upload_file_request::Type::Chunk(chunk)),
+        if let Err(err) = task_handle.await? {
+            error!(%err);
+            Err(err)?;
+        }
+
+        Ok(())
+    }
+
+    /// Downloads only blocks of the file which its existing copy at `local`
+    /// lacks. Returns the rebuilt file, which is to replace the copy, and
+    /// header of the file sent by the server. Delta transfers aren't retried.
+    async fn download_delta(
+        &mut self,
+        file: RemotePath,
+        local: &Path,
+        xattrs: bool,
+    ) -> Result<(TempFile, DownloadFileHeader)> {
+        let name = file.to_string();
+        let block_size = delta::block_size(fs::metadata(local).await?.len());
+        let signatures = delta::signatures(local, block_size).await?;
+        let idle_timeout = self.timeouts.idle;
+        let (tx, rx) = mpsc::channel(Self::CHANNEL_SIZE);
+
+        let task_handle = tokio::spawn(
+            async move {
+                let header = DownloadDeltaHeader {
+                    name: file.path,
+                    share: file.share,
+                    xattrs,
+                    block_size,
+                };
+                let message = DownloadDeltaRequest {
+                    r#type: Some(download_delta_request::Type::Header(header)),
+                };
+                send_message(&tx, message, idle_timeout).await?;
+
+                for blocks in signatures.chunks(Self::SIGNATURE_BATCH_SIZE) {
+                    let message = DownloadDeltaRequest {
+                        r#type: Some(download_delta_request::Type::Signatures(BlockSignatures {
+                            blocks: blocks.to_vec(),
+                        })),
                     };
+                    send_message(&tx, message, idle_timeout).await?;
+                }
+
+                Ok::<(), anyhow::Error>(())
+            }
+            .in_current_span(),
+        );
+
+        let call = self
+            .client
+            .download_delta(self.stream_request(ReceiverStream::new(rx)));
+        let mut delta_stream = response(call, idle_timeout).await?.into_inner();
 
-                    if let Err(err) = tx.send(request).await {
-                        error!(%err);
-                        Err(err)?;
+        let mut decoder = DeltaDecoder::new(local, block_size).await?;
+        let mut transfer = None;
+        let mut file_header = None;
+        let mut checksum = None;
+        let mut sent = 0;
+
+        let result = async {
+            while let Some(item) = next_message(&mut delta_stream, idle_timeout).await? {
+                let op = match item?.r#type {
+                    Some(download_delta_response::Type::Header(header)) => {
+                        transfer = Some(self.progress.start(&name, Some(header.size)));
+                        file_header = Some(header);
+                        continue;
+                    }
+                    Some(download_delta_response::Type::Copy(copy)) => DeltaOp::Copy {
+                        index: copy.index,
+                        count: copy.count,
+                    },
+                    Some(download_delta_response::Type::Data(data)) => {
+                        sent += data.len() as u64;
+                        DeltaOp::Data(data)
+                    }
+                    Some(download_delta_response::Type::Checksum(sum)) => {
+                        checksum = Some(sum);
+                        continue;
                     }
+                    None => continue,
+                };
+
+                let len = decoder.op_len(&op)?;
+                decoder.apply(op).await?;
+                if let Some(transfer) = &mut transfer {
+                    transfer.inc(len);
+                }
+            }
 
-                    if n < Self::CHUNK_SIZE_BYTES as usize {
-                        break;
+            task_handle.await??;
+
+            checksum.ok_or_else(|| anyhow!("Server didn't send checksum of {name}"))
+        }
+        .await;
+
+        // Dropping the decoder on error removes the partially rebuilt file
+        let temp_file = decoder.finish(&result?).await?;
+
+        if let Some(transfer) = transfer {
+            transfer.finish();
+        }
+
+        let header = file_header.unwrap_or_default();
+        info!("{name}: sent {sent} of {} bytes", header.size);
+
+        Ok((temp_file, header))
+    }
+
+    /// Signatures of blocks of the server's copy of `file` with their block
+    /// size, `None` if the server has no such file.
+    async fn get_signatures(
+        &mut self,
+        file: &RemotePath,
+    ) -> Result<Option<(u32, Vec<BlockSignature>)>> {
+        let request = GetSignaturesRequest {
+            name: file.path.clone(),
+            share: file.share.clone(),
+        };
+        let idle_timeout = self.timeouts.idle;
+
+        let call = self.client.get_signatures(self.request(request));
+        let mut signature_stream = match response(call, idle_timeout).await {
+            Ok(response) => response.into_inner(),
+            Err(status) if Self::lacks_signatures(&status) => return Ok(None),
+            Err(status) => Err(status)?,
+        };
+
+        let mut block_size = 0;
+        let mut signatures = Vec::new();
+
+        while let Some(item) = next_message(&mut signature_stream, idle_timeout).await? {
+            match item {
+                Ok(batch) => {
+                    block_size = batch.block_size;
+                    signatures.extend(batch.blocks);
+                }
+                Err(status) if Self::lacks_signatures(&status) => return Ok(None),
+                Err(status) => Err(status)?,
+            }
+        }
+
+        Ok(Some((block_size, signatures)))
+    }
+
+    /// Whether `status` means a delta upload can't start, but a whole one may
+    /// still work: the file doesn't exist yet, the share is write-only or the
+    /// server predates delta transfers.
+    fn lacks_signatures(status: &Status) -> bool {
+        matches!(
+            status.code(),
+            Code::NotFound | Code::PermissionDenied | Code::Unimplemented
+        )
+    }
+
+    /// Uploads only blocks of `file_path` which the server's copy described
+    /// by `signatures` lacks. Delta transfers aren't retried.
+    async fn upload_delta(
+        &mut self,
+        file: RemotePath,
+        file_path: &Path,
+        preserve: &PreserveArgs,
+        block_size: u32,
+        signatures: Vec<BlockSignature>,
+    ) -> Result<()> {
+        let name = file.to_string();
+        let input = fs::File::open(file_path).await?;
+        let metadata = input.metadata().await?;
+        let header = upload_header(&file, file_path, Some(&metadata), preserve).await?;
+        let size = metadata.len();
+        let mut transfer = self.progress.start(&name, Some(size));
+        let idle_timeout = self.timeouts.idle;
+        let (tx, rx) = mpsc::channel(Self::CHANNEL_SIZE);
+
+        let task_handle = tokio::spawn(
+            async move {
+                let header = UploadDeltaHeader {
+                    file: Some(header),
+                    block_size,
+                };
+                let message = UploadDeltaRequest {
+                    r#type: Some(upload_delta_request::Type::Header(header)),
+                };
+                send_message(&tx, message, idle_timeout).await?;
+
+                let (mut ops, encoder) =
+                    delta::spawn_encoder(input.into_std().await, block_size, signatures);
+
+                let mut position = 0;
+                while let Some(op) = ops.recv().await {
+                    let (message, n) = match op {
+                        // Only the last block of the file may be short
+                        DeltaOp::Copy { index, count } => (
+                            upload_delta_request::Type::Copy(CopyBlocks { index, count }),
+                            count
+                                .saturating_mul(block_size as u64)
+                                .min(size.saturating_sub(position)),
+                        ),
+                        DeltaOp::Data(data) => {
+                            let n = data.len() as u64;
+                            (upload_delta_request::Type::Data(data), n)
+                        }
+                    };
+                    let message = UploadDeltaRequest {
+                        r#type: Some(message),
+                    };
+                    send_message(&tx, message, idle_timeout).await?;
+                    position += n;
+                    transfer.inc(n);
+                }
+
+                let message = UploadDeltaRequest {
+                    r#type: Some(upload_delta_request::Type::Checksum(encoder.await??)),
+                };
+                send_message(&tx, message, idle_timeout).await?;
+
+                transfer.finish();
+
+                Ok::<(), anyhow::Error>(())
+            }
+            .in_current_span(),
+        );
+
+        let request = self.stream_request(ReceiverStream::new(rx));
+        let response = self.client.upload_delta(request).await?;
+
+        if let Err(err) = task_handle.await? {
+            error!(%err);
+            Err(err)?;
+        }
+
+        info!(
+            "{name}: sent {} of {} bytes",
+            response.get_ref().sent,
+            metadata.len()
+        );
+
+        Ok(())
+    }
+
+    /// Makes `directory` and `remote` directory on the server match as set
+    /// by `options`. Transferred files keep their modification time, so
+    /// that they compare equal afterwards.
+    #[instrument(skip(self))]
+    pub async fn sync(
+        &mut self,
+        remote: RemotePath,
+        directory: PathBuf,
+        options: SyncOptions,
+        checksums: bool,
+        dry_run: bool,
+        delta: bool,
+    ) -> Result<()> {
+        options.check()?;
+
+        // Missing target directory is empty, missing source is an error,
+        // so that --delete can't wipe out the target
+        let push = options.direction == SyncDirection::Push;
+        let pull = options.direction == SyncDirection::Pull;
+        let remote_files = self.list_tree(&remote, checksums, push).await?;
+        let local_files = sync::local_tree(&directory, checksums, pull).await?;
+        let actions = sync::plan(&local_files, &remote_files, &options);
+
+        let preserve = PreserveArgs {
+            preserve: true,
+            xattrs: false,
+            xattr_namespaces: vec![],
+        };
+        let mut results = Vec::with_capacity(actions.len());
+
+        for (name, action) in actions {
+            if dry_run {
+                results.push((name, action, None));
+                continue;
+            }
+
+            let remote_file = RemotePath {
+                share: remote.share.clone(),
+                path: match remote.path.trim_end_matches('/') {
+                    "" => name.clone(),
+                    path => format!("{path}/{name}"),
+                },
+            };
+            let local = directory.join(&name);
+
+            let result = match action {
+                SyncAction::Upload => {
+                    self.upload_file(
+                        remote_file,
+                        directory.clone(),
+                        Some(local),
+                        preserve.clone(),
+                        delta,
+                    )
+                    .await
+                }
+                SyncAction::Download => {
+                    if let Some(parent) = local.parent() {
+                        fs::create_dir_all(parent).await?;
                     }
+                    self.download_file(remote_file, local, &preserve, delta)
+                        .await
                 }
+                SyncAction::DeleteLocal => fs::remove_file(&local).await.map_err(Into::into),
+                SyncAction::DeleteRemote => self.delete_file(remote_file).await,
+                SyncAction::Skip => Ok(()),
+            };
+            if let Err(err) = &result {
+                error!(file = name, "{err:#}");
+            }
+
+            results.push((name, action, Some(result.map_err(|err| format!("{err:#}")))));
+        }
+
+        let failed = results
+            .iter()
+            .filter(|(_, _, result)| matches!(result, Some(Err(_))))
+            .count();
+        let total = results.len();
+        println!("{}", SyncOutputPrint::from(results));
+
+        if failed > 0 {
+            return Err(anyhow!("{failed} of {total} sync actions failed"));
+        }
+
+        Ok(())
+    }
+
+    /// Regular files under `directory` on the server. A missing directory is
+    /// empty if `missing_ok` is set.
+    async fn list_tree(
+        &mut self,
+        directory: &RemotePath,
+        checksums: bool,
+        missing_ok: bool,
+    ) -> Result<BTreeMap<String, SyncFile>> {
+        let request = ListTreeRequest {
+            name: directory.path.clone(),
+            share: directory.share.clone(),
+            checksums,
+        };
+        let idle_timeout = self.timeouts.idle;
+
+        let call = self.client.list_tree(self.request(request));
+        let mut tree_stream = response(call, idle_timeout).await?.into_inner();
+        let mut files = BTreeMap::new();
+
+        while let Some(item) = next_message(&mut tree_stream, idle_timeout).await? {
+            let file = match item {
+                Ok(file) => file,
+                Err(status) if status.code() == Code::NotFound && missing_ok => break,
+                Err(status) => Err(status)?,
+            };
+            // Names are joined to the local directory, which they mustn't leave
+            let path = Path::new(&file.name);
+            if path.as_os_str().is_empty()
+                || !path
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+            {
+                return Err(anyhow!("Server sent invalid file name {:?}", file.name));
+            }
+
+            files.insert(
+                file.name,
+                SyncFile {
+                    size: file.size,
+                    modified: file.modified,
+                    checksum: checksums.then_some(file.checksum),
+                },
+            );
+        }
+
+        Ok(files)
+    }
+
+    /// Deletes a file on the server.
+    #[instrument(skip(self))]
+    pub async fn delete_file(&mut self, file: RemotePath) -> Result<()> {
+        let request = DeleteFileRequest {
+            name: file.path,
+            share: file.share,
+        };
+        self.client.delete_file(self.request(request)).await?;
+
+        Ok(())
+    }
+
+    /// Downloads archive of a remote directory to `local` path, `-` being
+    /// standard output. Archives are built while they are sent, so failed
+    /// downloads aren't resumed.
+    #[instrument(skip(self))]
+    pub async fn download_archive(
+        &mut self,
+        directory: RemotePath,
+        format: ArchiveFormat,
+        local: PathBuf,
+    ) -> Result<()> {
+        let name = archive_name(&directory);
+        let mut request = DownloadArchiveRequest {
+            name: directory.path,
+            share: directory.share,
+            ..Default::default()
+        };
+        request.set_format(archive_format(format));
+
+        if is_stdio(&local) {
+            let mut stdout = io::stdout();
+            self.download_archive_to(request, &name, &mut stdout)
+                .await?;
+            stdout.flush().await?;
+        } else {
+            let (temp_file, mut output) = TempFile::create(&local).await?;
+            self.download_archive_to(request, &name, &mut output)
+                .await?;
+            output.sync_all().await?;
+            temp_file.persist().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn download_archive_to<W>(
+        &mut self,
+        request: DownloadArchiveRequest,
+        name: &str,
+        output: &mut W,
+    ) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let call = self.client.download_archive(self.request(request));
+        let response = response(call, self.timeouts.idle).await?;
+
+        let mut archive_stream = response.into_inner();
+        let mut transfer = self.progress.start(name, None);
+
+        while let Some(item) = next_message(&mut archive_stream, self.timeouts.idle).await? {
+            let chunk = item?.chunk;
+            output.write_all(&chunk).await?;
+            transfer.inc(chunk.len() as u64);
+        }
+
+        transfer.finish();
+
+        Ok(())
+    }
+
+    /// Uploads archive from `local` path, `-` being standard input, to be
+    /// extracted by the server into a remote directory.
+    #[instrument(skip(self))]
+    pub async fn upload_archive(
+        &mut self,
+        directory: RemotePath,
+        format: ArchiveFormat,
+        local: PathBuf,
+    ) -> Result<()> {
+        let (tx, rx) = mpsc::channel(Self::CHANNEL_SIZE);
+
+        let receiver_stream = ReceiverStream::new(rx);
+
+        let (input, metadata) = open_input(&local).await?;
+        let size = metadata.map(|metadata| metadata.len());
+        let mut transfer = self.progress.start(&archive_name(&directory), size);
+        let idle_timeout = self.timeouts.idle;
+
+        let task_handle = tokio::spawn(
+            async move {
+                let mut header = UploadArchiveHeader {
+                    name: directory.path,
+                    share: directory.share,
+                    ..Default::default()
+                };
+                header.set_format(archive_format(format));
+                if let Err(err) = tx
+                    .send(UploadArchiveRequest {
+                        r#type: Some(upload_archive_request::Type::Header(header)),
+                    })
+                    .await
+                {
+                    error!(%err);
+                    Err(err)?;
+                }
+
+                send_chunks(input, &tx, idle_timeout, &mut transfer, |chunk| {
+                    UploadArchiveRequest {
+                        r#type: Some(upload_archive_request::Type::Chunk(chunk)),
+                    }
+                })
+                .await?;
+
+                transfer.finish();
 
                 Ok::<(), anyhow::Error>(())
             }
             .in_current_span(),
         );
 
-        self.client.upload_file(receiver_stream).await?;
+        let request = self.stream_request(receiver_stream);
+        let response = self.client.upload_archive(request).await?;
 
         if let Err(err) = task_handle.await? {
             error!(%err);
             Err(err)?;
         }
 
+        println!("Extracted {} files", response.get_ref().files);
+
         Ok(())
     }
 }
+
+/// Header of an uploaded file, with its metadata as requested by `preserve`.
+async fn upload_header(
+    file: &RemotePath,
+    file_path: &Path,
+    metadata: Option<&Metadata>,
+    preserve: &PreserveArgs,
+) -> Result<UploadFileHeader> {
+    let xattrs = match (preserve.xattr_namespaces(), metadata) {
+        (Some(namespaces), Some(_)) => attributes::read_xattrs(file_path, &namespaces).await?,
+        _ => vec![],
+    };
+
+    Ok(UploadFileHeader {
+        name: file.path.clone(),
+        share: file.share.clone(),
+        size: metadata.map(|metadata| metadata.len()),
+        attributes: metadata
+            .filter(|_| preserve.preserve)
+            .map(attributes::from_metadata),
+        xattrs,
+    })
+}
+
+/// Opens local file for upload, `-` being standard input, with its
+/// metadata if it's a file.
+async fn open_input(path: &Path) -> Result<(Box<dyn AsyncRead + Unpin + Send>, Option<Metadata>)> {
+    // Size of standard input is only known once it's read
+    if is_stdio(path) {
+        return Ok((Box::new(io::stdin()), None));
+    }
+
+    let input = fs::File::open(path).await?;
+    let metadata = input.metadata().await?;
+
+    Ok((Box::new(input), Some(metadata)))
+}
+
+/// Sends `input` to a client stream in chunks wrapped into messages.
+async fn send_chunks<M>(
+    input: Box<dyn AsyncRead + Unpin + Send>,
+    tx: &mpsc::Sender<M>,
+    idle_timeout: Option<Duration>,
+    transfer: &mut Transfer,
+    message: impl Fn(Vec<u8>) -> M,
+) -> Result<()> {
+    const CHUNK_SIZE_BYTES: u64 = FileClient::<Channel>::CHUNK_SIZE_BYTES;
+
+    let mut handle = input.take(CHUNK_SIZE_BYTES);
+
+    loop {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE_BYTES as usize);
+
+        let n = handle.read_to_end(&mut chunk).await?;
+
+        if 0 == n {
+            break;
+        } else {
+            handle.set_limit(CHUNK_SIZE_BYTES);
+        }
+
+        if let Err(err) = send_message(tx, message(chunk), idle_timeout).await {
+            error!(%err);
+            Err(err)?;
+        }
+
+        transfer.inc(n as u64);
+
+        if n < CHUNK_SIZE_BYTES as usize {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends pieces of a sparse file to a client stream wrapped into messages.
+async fn send_pieces<M>(
+    mut reader: SparseReader,
+    tx: &mpsc::Sender<M>,
+    idle_timeout: Option<Duration>,
+    transfer: &mut Transfer,
+    message: impl Fn(Piece) -> M,
+) -> Result<()> {
+    const CHUNK_SIZE_BYTES: u64 = FileClient::<Channel>::CHUNK_SIZE_BYTES;
+
+    while let Some(piece) = reader.next(CHUNK_SIZE_BYTES).await? {
+        let n = match &piece {
+            Piece::Data(chunk) => chunk.len() as u64,
+            Piece::Hole(len) => *len,
+        };
+
+        if let Err(err) = send_message(tx, message(piece), idle_timeout).await {
+            error!(%err);
+            Err(err)?;
+        }
+
+        transfer.inc(n);
+    }
+
+    Ok(())
+}
+
+/// Name under which archive transfers are reported.
+fn archive_name(directory: &RemotePath) -> String {
+    match directory.to_string() {
+        name if name.is_empty() => "archive".to_string(),
+        name => name,
+    }
+}
+
+fn archive_format(format: ArchiveFormat) -> api::ArchiveFormat {
+    match format {
+        ArchiveFormat::Tar => api::ArchiveFormat::Tar,
+        ArchiveFormat::TarZst => api::ArchiveFormat::TarZstd,
+        ArchiveFormat::Zip => api::ArchiveFormat::Zip,
+    }
+}
@@ -1,45 +1,164 @@
 pub mod cli;
+mod config;
 mod file_client;
 mod output_print;
+mod progress;
+mod retry;
+mod sync;
+mod timeouts;
 
-use crate::cli::{Cli, Commands::*};
+use crate::{
+    cli::{Cli, Commands::*},
+    config::Config,
+};
 use anyhow::Result;
 use file_client::FileClient;
+use progress::Progress;
+use retry::RetryPolicy;
+use sync::SyncOptions;
+use timeouts::Timeouts;
 
 pub async fn client_main(args: &Cli) -> Result<()> {
+    let config = Config::load(args)?;
+
     let mut ca_cert_pem_str = None;
-    if let Some(ca_cert_pem) = &args.ca_cert {
+    if let Some(ca_cert_pem) = &config.ca_cert {
         ca_cert_pem_str = Some(std::fs::read_to_string(ca_cert_pem)?);
     }
 
     let mut cert_pem_str = None;
-    if let Some(cert_pem) = &args.cert {
+    if let Some(cert_pem) = &config.cert {
         cert_pem_str = Some(std::fs::read_to_string(cert_pem)?);
     }
 
     let mut key_pem_str = None;
-    if let Some(key_pem) = &args.key {
+    if let Some(key_pem) = &config.key {
         key_pem_str = Some(std::fs::read_to_string(key_pem)?);
     }
 
     let mut client = FileClient::new(
-        &args.address,
-        args.port,
+        &config.address,
+        config.port,
         ca_cert_pem_str.as_deref(),
         cert_pem_str.as_deref(),
         key_pem_str.as_deref(),
+        config.token.as_deref(),
+        RetryPolicy {
+            retries: args.retry.retries,
+            backoff: args.retry.retry_backoff,
+            max_backoff: args.retry.retry_max_backoff,
+            jitter: args.retry.retry_jitter,
+        },
+        Timeouts {
+            connect: Some(args.timeouts.connect_timeout),
+            request: args.timeouts.request_timeout,
+            idle: (!args.timeouts.idle_timeout.is_zero()).then_some(args.timeouts.idle_timeout),
+            keepalive_interval: args.timeouts.keepalive_interval,
+            keepalive_timeout: args.timeouts.keepalive_timeout,
+        },
     )
-    .await?;
+    .await?
+    .with_progress(Progress::new(args.quiet));
 
     match &args.command {
-        List => &mut client.list_files().await?,
-        Download { file, directory } => {
+        List {
+            share,
+            filter,
+            output,
+            bytes,
+        } => {
+            &mut client
+                .list_files(share.clone().unwrap_or_default(), filter, *output, *bytes)
+                .await?
+        }
+        Shares => &mut client.list_shares().await?,
+        Capabilities { share } => {
+            &mut client
+                .get_capabilities(share.clone().unwrap_or_default())
+                .await?
+        }
+        Download {
+            files,
+            directory,
+            jobs,
+            local,
+            preserve,
+            delta,
+        } => {
+            let directory = directory.as_ref().unwrap_or(&config.directory);
+            &mut client
+                .download_files(
+                    files.clone(),
+                    directory.clone(),
+                    local.clone(),
+                    *jobs as usize,
+                    preserve.clone(),
+                    *delta,
+                )
+                .await?
+        }
+        Upload {
+            file,
+            directory,
+            local,
+            preserve,
+            delta,
+        } => {
+            let directory = directory.as_ref().unwrap_or(&config.directory);
             &mut client
-                .download_file(file.clone(), directory.clone())
+                .upload_file(
+                    file.clone(),
+                    directory.clone(),
+                    local.clone(),
+                    preserve.clone(),
+                    *delta,
+                )
                 .await?
         }
-        Upload { file, directory } => {
-            &mut client.upload_file(file.clone(), directory.clone()).await?
+        DownloadArchive {
+            remote,
+            format,
+            local,
+        } => {
+            &mut client
+                .download_archive(remote.clone().unwrap_or_default(), *format, local.clone())
+                .await?
+        }
+        UploadArchive {
+            remote,
+            format,
+            local,
+        } => {
+            &mut client
+                .upload_archive(remote.clone().unwrap_or_default(), *format, local.clone())
+                .await?
+        }
+        Sync {
+            remote,
+            directory,
+            direction,
+            conflict,
+            delete,
+            checksum,
+            dry_run,
+            delta,
+        } => {
+            let directory = directory.as_ref().unwrap_or(&config.directory);
+            let options = SyncOptions {
+                direction: *direction,
+                conflict: *conflict,
+                delete: *delete,
+            };
+            &mut client
+                .sync(
+                    remote.clone().unwrap_or_default(),
+                    directory.clone(),
+                    options,
+                    *checksum,
+                    *dry_run,
+                    *delta,
+                )
+                .await?
         }
     };
 
@@ -1,35 +1,141 @@
+use crate::{cli::OutputFormat, sync::SyncAction};
 use comfy_table::{presets::NOTHING, Cell, Table};
-use proto::api::ListFilesResponse;
-use std::fmt;
-use ubyte::{ByteUnit, ToByteUnit};
+use proto::api::{GetCapabilitiesResponse, ListFilesResponse, ShareInfo, ShareMode};
+use serde::Serialize;
+use std::{
+    fmt,
+    time::{Duration, UNIX_EPOCH},
+};
+use ubyte::ToByteUnit;
 
+#[derive(Serialize)]
 struct FileOutputPrint {
     name: String,
-    size: ByteUnit,
+    size: u64,
+    /// RFC 3339 timestamp, omitted if unknown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<String>,
 }
 
 impl FileOutputPrint {
-    pub fn new(name: &str, size: u64) -> Self {
+    pub fn new(name: &str, size: u64, modified: u64) -> Self {
+        let modified = (modified > 0).then(|| {
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(modified))
+                .to_string()
+        });
+
         FileOutputPrint {
             name: name.to_string(),
-            size: size.bytes(),
+            size,
+            modified,
         }
     }
 }
 
 pub struct FilesOutputPrint {
     files: Vec<FileOutputPrint>,
+    format: OutputFormat,
+    bytes: bool,
+    /// Whether table or CSV/TSV header is printed, only for the first page
+    header: bool,
+}
+
+impl FilesOutputPrint {
+    /// Sets output format and whether table sizes are printed in bytes.
+    pub fn format(self, format: OutputFormat, bytes: bool) -> Self {
+        FilesOutputPrint {
+            format,
+            bytes,
+            ..self
+        }
+    }
+
+    /// Leaves out header of continued listing.
+    fn without_header(self) -> Self {
+        FilesOutputPrint {
+            header: false,
+            ..self
+        }
+    }
+
+    fn fmt_table(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut table = Table::new();
+        table.load_preset(NOTHING);
+        if self.header {
+            table.set_header(vec!["File name", "Size"]);
+        }
+
+        for file in &self.files {
+            let size = if self.bytes {
+                Cell::new(file.size)
+            } else {
+                Cell::new(file.size.bytes())
+            };
+            table.add_row(vec![Cell::new(file.name.clone()), size]);
+        }
+
+        write!(f, "{table}")
+    }
+
+    fn fmt_separated(
+        &self,
+        f: &mut fmt::Formatter,
+        separator: char,
+        escape: fn(&str) -> String,
+    ) -> fmt::Result {
+        if self.header {
+            write!(f, "name{separator}size{separator}modified")?;
+        }
+
+        for (i, file) in self.files.iter().enumerate() {
+            if self.header || i > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "{}{separator}{}{separator}{}",
+                escape(&file.name),
+                file.size,
+                file.modified.as_deref().unwrap_or_default()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Quotes CSV field if needed, as described in RFC 4180.
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Escapes characters which would break TSV row.
+fn escape_tsv(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
 }
 
 impl From<ListFilesResponse> for FileOutputPrint {
     fn from(file_resp: ListFilesResponse) -> Self {
-        FileOutputPrint::new(&file_resp.name, file_resp.size)
+        FileOutputPrint::new(&file_resp.name, file_resp.size, file_resp.modified)
     }
 }
 
 impl From<Vec<FileOutputPrint>> for FilesOutputPrint {
     fn from(files: Vec<FileOutputPrint>) -> Self {
-        FilesOutputPrint { files }
+        FilesOutputPrint {
+            files,
+            format: OutputFormat::default(),
+            bytes: false,
+            header: true,
+        }
     }
 }
 
@@ -44,14 +150,236 @@ impl From<Vec<ListFilesResponse>> for FilesOutputPrint {
 }
 
 impl fmt::Display for FilesOutputPrint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.format {
+            OutputFormat::Table => self.fmt_table(f),
+            OutputFormat::Json => {
+                let json = serde_json::to_string_pretty(&self.files).map_err(|_| fmt::Error)?;
+                write!(f, "{json}")
+            }
+            OutputFormat::Csv => self.fmt_separated(f, ',', escape_csv),
+            OutputFormat::Tsv => self.fmt_separated(f, '\t', escape_tsv),
+        }
+    }
+}
+
+/// Prints listing page by page as pages arrive, still as a single CSV/TSV
+/// document or JSON array. Table columns fit the widest cell, so tables are
+/// printed only once all pages came.
+pub struct FilesPagePrinter {
+    format: OutputFormat,
+    bytes: bool,
+    pages: usize,
+    /// Files printed so far, JSON array elements need separators
+    files: usize,
+    /// Files of the table, printed by [`FilesPagePrinter::finish`]
+    table: Vec<ListFilesResponse>,
+}
+
+impl FilesPagePrinter {
+    pub fn new(format: OutputFormat, bytes: bool) -> Self {
+        FilesPagePrinter {
+            format,
+            bytes,
+            pages: 0,
+            files: 0,
+            table: Vec::new(),
+        }
+    }
+
+    /// Whether no page was printed yet.
+    pub fn is_empty(&self) -> bool {
+        self.pages == 0
+    }
+
+    pub fn print_page(&mut self, files: Vec<ListFilesResponse>) -> serde_json::Result<()> {
+        let first = self.pages == 0;
+        self.pages += 1;
+
+        match self.format {
+            OutputFormat::Table => {
+                self.table.extend(files);
+                return Ok(());
+            }
+            OutputFormat::Json => {
+                if first {
+                    print!("[");
+                }
+                for file in files {
+                    let json = serde_json::to_string_pretty(&FileOutputPrint::from(file))?;
+                    let separator = if self.files > 0 { "," } else { "" };
+                    print!("{separator}\n  {}", json.replace('\n', "\n  "));
+                    self.files += 1;
+                }
+                return Ok(());
+            }
+            OutputFormat::Csv | OutputFormat::Tsv => {}
+        }
+
+        if files.is_empty() && !first {
+            return Ok(());
+        }
+        let page = FilesOutputPrint::from(files).format(self.format, self.bytes);
+        if first {
+            println!("{page}");
+        } else {
+            println!("{}", page.without_header());
+        }
+
+        Ok(())
+    }
+
+    /// Ends the listing, printing an empty one if no page came.
+    pub fn finish(mut self) -> serde_json::Result<()> {
+        if self.pages == 0 {
+            self.print_page(vec![])?;
+        }
+
+        match self.format {
+            OutputFormat::Table => {
+                let table = FilesOutputPrint::from(self.table).format(self.format, self.bytes);
+                println!("{table}");
+            }
+            OutputFormat::Json if self.files > 0 => println!("\n]"),
+            OutputFormat::Json => println!("]"),
+            OutputFormat::Csv | OutputFormat::Tsv => {}
+        }
+
+        Ok(())
+    }
+}
+
+pub struct SharesOutputPrint {
+    shares: Vec<ShareInfo>,
+}
+
+impl From<Vec<ShareInfo>> for SharesOutputPrint {
+    fn from(shares: Vec<ShareInfo>) -> Self {
+        SharesOutputPrint { shares }
+    }
+}
+
+impl fmt::Display for SharesOutputPrint {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut table = Table::new();
         table
-            .set_header(vec!["File name", "Size"])
+            .set_header(vec!["Share name", "Access", "Quota"])
             .load_preset(NOTHING);
 
-        for file in &self.files {
-            table.add_row(vec![Cell::new(file.name.clone()), Cell::new(file.size)]);
+        for share in &self.shares {
+            let access = mode_name(share.mode());
+            let quota = match share.quota {
+                0 => Cell::new("-"),
+                quota => Cell::new(quota.bytes()),
+            };
+            table.add_row(vec![
+                Cell::new(share.name.clone()),
+                Cell::new(access),
+                quota,
+            ]);
+        }
+
+        write!(f, "{table}")
+    }
+}
+
+fn mode_name(mode: ShareMode) -> &'static str {
+    match mode {
+        ShareMode::ReadWrite => "read-write",
+        ShareMode::ReadOnly => "read-only",
+        ShareMode::WriteOnly => "write-only",
+    }
+}
+
+pub struct CapabilitiesOutputPrint {
+    capabilities: GetCapabilitiesResponse,
+}
+
+impl From<GetCapabilitiesResponse> for CapabilitiesOutputPrint {
+    fn from(capabilities: GetCapabilitiesResponse) -> Self {
+        CapabilitiesOutputPrint { capabilities }
+    }
+}
+
+impl fmt::Display for CapabilitiesOutputPrint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let allowed = |allowed: bool| if allowed { "allowed" } else { "denied" };
+
+        let mut table = Table::new();
+        table
+            .set_header(vec!["Capability", "Value"])
+            .load_preset(NOTHING)
+            .add_row(vec!["mode", mode_name(self.capabilities.mode())])
+            .add_row(vec!["list", allowed(self.capabilities.list)])
+            .add_row(vec!["download", allowed(self.capabilities.download)])
+            .add_row(vec!["upload", allowed(self.capabilities.upload)]);
+
+        write!(f, "{table}")
+    }
+}
+
+/// Outcome of every file transferred by a single command.
+pub struct TransfersOutputPrint {
+    transfers: Vec<(String, Result<(), String>)>,
+}
+
+impl From<Vec<(String, Result<(), String>)>> for TransfersOutputPrint {
+    fn from(transfers: Vec<(String, Result<(), String>)>) -> Self {
+        TransfersOutputPrint { transfers }
+    }
+}
+
+impl fmt::Display for TransfersOutputPrint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut table = Table::new();
+        table
+            .set_header(vec!["File name", "Result"])
+            .load_preset(NOTHING);
+
+        for (name, result) in &self.transfers {
+            let result = match result {
+                Ok(()) => "ok".to_string(),
+                Err(err) => format!("failed: {err}"),
+            };
+            table.add_row(vec![Cell::new(name), Cell::new(result)]);
+        }
+
+        write!(f, "{table}")
+    }
+}
+
+/// File name, action and its result, `None` for a dry run.
+pub type SyncResult = (String, SyncAction, Option<Result<(), String>>);
+
+/// Actions taken by a sync, or planned by a dry run.
+pub struct SyncOutputPrint {
+    actions: Vec<SyncResult>,
+}
+
+impl From<Vec<SyncResult>> for SyncOutputPrint {
+    fn from(actions: Vec<SyncResult>) -> Self {
+        SyncOutputPrint { actions }
+    }
+}
+
+impl fmt::Display for SyncOutputPrint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.actions.is_empty() {
+            return write!(f, "Already in sync");
+        }
+
+        let mut table = Table::new();
+        table
+            .set_header(vec!["File name", "Action", "Result"])
+            .load_preset(NOTHING);
+
+        for (name, action, result) in &self.actions {
+            let result = match result {
+                None => "dry run".to_string(),
+                Some(Ok(())) => "ok".to_string(),
+                Some(Err(err)) => format!("failed: {err}"),
+            };
+            table.add_row(vec![Cell::new(name), Cell::new(action), Cell::new(result)]);
         }
 
         write!(f, "{table}")
@@ -0,0 +1,145 @@
+use console::user_attended_stderr;
+use indicatif::{
+    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
+};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Reports progress of transfers, as bars when stderr is a terminal and as
+/// periodic log lines otherwise.
+#[derive(Clone, Default)]
+pub struct Progress {
+    mode: Mode,
+}
+
+#[derive(Clone, Default)]
+enum Mode {
+    #[default]
+    Quiet,
+    Bars(MultiProgress),
+    Log,
+}
+
+impl Progress {
+    pub fn new(quiet: bool) -> Self {
+        let mode = if quiet {
+            Mode::Quiet
+        } else if user_attended_stderr() {
+            Mode::Bars(MultiProgress::with_draw_target(ProgressDrawTarget::stderr()))
+        } else {
+            Mode::Log
+        };
+
+        Progress { mode }
+    }
+
+    /// Starts reporting transfer of a file of `total` bytes, if known.
+    pub fn start(&self, name: &str, total: Option<u64>) -> Transfer {
+        let bar = match &self.mode {
+            Mode::Bars(bars) => {
+                let bar = match total {
+                    Some(total) => ProgressBar::new(total).with_style(
+                        ProgressStyle::with_template(
+                            "{msg} [{bar:30}] {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta}",
+                        )
+                        .unwrap()
+                        .progress_chars("=> "),
+                    ),
+                    None => ProgressBar::new_spinner().with_style(
+                        ProgressStyle::with_template(
+                            "{spinner} {msg} {bytes} {binary_bytes_per_sec}",
+                        )
+                        .unwrap(),
+                    ),
+                };
+                Some(bars.add(bar.with_message(name.to_string())))
+            }
+            Mode::Quiet | Mode::Log => None,
+        };
+
+        let now = Instant::now();
+        Transfer {
+            name: name.to_string(),
+            total,
+            done: 0,
+            bar,
+            log: matches!(self.mode, Mode::Log),
+            started: now,
+            logged: now,
+        }
+    }
+}
+
+/// Progress of a single file transfer.
+pub struct Transfer {
+    name: String,
+    total: Option<u64>,
+    done: u64,
+    bar: Option<ProgressBar>,
+    log: bool,
+    started: Instant,
+    logged: Instant,
+}
+
+impl Transfer {
+    const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+    pub fn inc(&mut self, bytes: u64) {
+        self.done += bytes;
+
+        if let Some(bar) = &self.bar {
+            bar.inc(bytes);
+        }
+
+        if self.log && self.logged.elapsed() >= Self::LOG_INTERVAL {
+            self.logged = Instant::now();
+            self.log_progress();
+        }
+    }
+
+    pub fn finish(self) {
+        if let Some(bar) = &self.bar {
+            bar.finish();
+        }
+
+        if self.log {
+            self.log_progress();
+        }
+    }
+
+    fn log_progress(&self) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            self.done as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        match self.total {
+            Some(total) => {
+                let percent = (self.done * 100).checked_div(total).unwrap_or(100);
+                let eta = if rate > 0.0 {
+                    let remaining = total.saturating_sub(self.done) as f64 / rate;
+                    HumanDuration(Duration::from_secs_f64(remaining)).to_string()
+                } else {
+                    "unknown".to_string()
+                };
+                info!(
+                    "{}: {} of {} ({}%), {}/s, ETA {}",
+                    self.name,
+                    HumanBytes(self.done),
+                    HumanBytes(total),
+                    percent,
+                    HumanBytes(rate as u64),
+                    eta
+                );
+            }
+            None => info!(
+                "{}: {}, {}/s",
+                self.name,
+                HumanBytes(self.done),
+                HumanBytes(rate as u64)
+            ),
+        }
+    }
+}
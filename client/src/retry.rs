@@ -0,0 +1,83 @@
+use anyhow::Error;
+use std::time::Duration;
+use tonic::{Code, Status};
+
+/// How failed requests are retried. Delays grow exponentially from `backoff`
+/// up to `max_backoff`.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt, 0 disables retrying
+    pub retries: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Fraction of a delay randomly taken off it, from 0 to 1, so that
+    /// clients failed at once don't retry at once
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 0,
+            backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+            jitter: 0.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt`, counted from 1, or `None` when
+    /// retries are used up.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt == 0 || attempt > self.retries {
+            return None;
+        }
+
+        let delay = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_backoff);
+
+        Some(delay - delay.mul_f64(self.jitter * fastrand::f64()))
+    }
+}
+
+/// Whether the error is likely to go away on its own, e.g. server restart,
+/// dropped connection or rate limiting.
+pub fn is_transient(err: &Error) -> bool {
+    if err.downcast_ref::<tonic::transport::Error>().is_some() {
+        return true;
+    }
+
+    let Some(status) = err.downcast_ref::<Status>() else {
+        return false;
+    };
+
+    match status.code() {
+        Code::Unavailable => true,
+        Code::ResourceExhausted => retry_after(err).is_some(),
+        // Connection dropped in the middle of a stream
+        Code::Unknown | Code::Internal => {
+            let message = status.message().to_lowercase();
+            ["transport error", "connection", "broken pipe"]
+                .iter()
+                .any(|cause| message.contains(cause))
+        }
+        _ => false,
+    }
+}
+
+/// Delay requested by a rate limiting server.
+pub fn retry_after(err: &Error) -> Option<Duration> {
+    let seconds = err
+        .downcast_ref::<Status>()?
+        .metadata()
+        .get("retry-after")?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+
+    Some(Duration::from_secs(seconds))
+}
@@ -0,0 +1,177 @@
+use crate::cli::{ConflictPolicy, SyncDirection};
+use anyhow::{anyhow, Result};
+use common::{delta, temp_file};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tokio::fs;
+
+/// Regular file on either side of a sync, keyed by its `/` separated path
+/// relative to the synced directory.
+#[derive(Clone, Debug)]
+pub struct SyncFile {
+    pub size: u64,
+    /// Last modification time in seconds since Unix epoch
+    pub modified: u64,
+    /// SHA-256, only if files are compared by checksum
+    pub checksum: Option<Vec<u8>>,
+}
+
+impl SyncFile {
+    /// Whether both versions are the same, by checksum if both have one and
+    /// by size and modification time otherwise.
+    fn same(&self, other: &SyncFile) -> bool {
+        match (&self.checksum, &other.checksum) {
+            (Some(checksum), Some(other_checksum)) => checksum == other_checksum,
+            _ => self.size == other.size && self.modified == other.modified,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncAction {
+    Upload,
+    Download,
+    DeleteLocal,
+    DeleteRemote,
+    /// File differs on both sides and the conflict policy keeps both
+    Skip,
+}
+
+impl Display for SyncAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            SyncAction::Upload => "upload",
+            SyncAction::Download => "download",
+            SyncAction::DeleteLocal => "delete local",
+            SyncAction::DeleteRemote => "delete remote",
+            SyncAction::Skip => "skip conflict",
+        };
+
+        write!(f, "{action}")
+    }
+}
+
+/// How `sync` makes both sides match.
+#[derive(Clone, Copy, Debug)]
+pub struct SyncOptions {
+    pub direction: SyncDirection,
+    pub conflict: ConflictPolicy,
+    pub delete: bool,
+}
+
+impl SyncOptions {
+    pub fn check(&self) -> Result<()> {
+        if self.delete && self.direction == SyncDirection::TwoWay {
+            return Err(anyhow!("--delete only works with push or pull"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Actions which make both sides match, in path order.
+pub fn plan(
+    local: &BTreeMap<String, SyncFile>,
+    remote: &BTreeMap<String, SyncFile>,
+    options: &SyncOptions,
+) -> Vec<(String, SyncAction)> {
+    let names: BTreeSet<&String> = local.keys().chain(remote.keys()).collect();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let action = match (local.get(name), remote.get(name), options.direction) {
+                (Some(local), Some(remote), _) if local.same(remote) => return None,
+                (Some(_), None, SyncDirection::Pull) if options.delete => SyncAction::DeleteLocal,
+                (Some(_), None, SyncDirection::Pull) => return None,
+                (None, Some(_), SyncDirection::Push) if options.delete => SyncAction::DeleteRemote,
+                (None, Some(_), SyncDirection::Push) => return None,
+                (Some(_), _, SyncDirection::Push) | (Some(_), None, SyncDirection::TwoWay) => {
+                    SyncAction::Upload
+                }
+                (_, Some(_), SyncDirection::Pull) | (None, Some(_), SyncDirection::TwoWay) => {
+                    SyncAction::Download
+                }
+                (Some(local), Some(remote), SyncDirection::TwoWay) => {
+                    resolve_conflict(local, remote, options.conflict)
+                }
+                (None, None, _) => return None,
+            };
+
+            Some((name.clone(), action))
+        })
+        .collect()
+}
+
+fn resolve_conflict(local: &SyncFile, remote: &SyncFile, conflict: ConflictPolicy) -> SyncAction {
+    match conflict {
+        ConflictPolicy::Newer if local.modified > remote.modified => SyncAction::Upload,
+        ConflictPolicy::Newer if local.modified < remote.modified => SyncAction::Download,
+        ConflictPolicy::Local => SyncAction::Upload,
+        ConflictPolicy::Remote => SyncAction::Download,
+        ConflictPolicy::Newer | ConflictPolicy::Skip => SyncAction::Skip,
+    }
+}
+
+/// Regular files under `directory`, symbolic links are left out like on
+/// the server. A missing directory is empty if `missing_ok` is set.
+pub async fn local_tree(
+    directory: &Path,
+    checksums: bool,
+    missing_ok: bool,
+) -> Result<BTreeMap<String, SyncFile>> {
+    let mut files = BTreeMap::new();
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(relative) = pending.pop() {
+        let mut dir_stream = match fs::read_dir(directory.join(&relative)).await {
+            Ok(dir_stream) => dir_stream,
+            Err(err) if err.kind() == ErrorKind::NotFound && missing_ok => break,
+            Err(err) => return Err(anyhow!("Failed to read {directory:?}: {err}")),
+        };
+
+        while let Some(dir_entry) = dir_stream.next_entry().await? {
+            // Files of transfers in progress
+            if temp_file::is_temp(&dir_entry.file_name()) {
+                continue;
+            }
+            let metadata = fs::symlink_metadata(dir_entry.path()).await?;
+            let name = relative.join(dir_entry.file_name());
+
+            if metadata.is_dir() {
+                pending.push(name);
+            } else if metadata.is_file() {
+                let name = name
+                    .to_str()
+                    .ok_or_else(|| anyhow!("Invalid file name: {name:?}"))?
+                    .to_string();
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |modified| modified.as_secs());
+                let checksum = if checksums {
+                    Some(delta::checksum(&dir_entry.path()).await?)
+                } else {
+                    None
+                };
+
+                files.insert(
+                    name,
+                    SyncFile {
+                        size: metadata.len(),
+                        modified,
+                        checksum,
+                    },
+                );
+            }
+        }
+    }
+
+    Ok(files)
+}
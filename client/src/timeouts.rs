@@ -0,0 +1,105 @@
+// Helpers return `tonic::Status` like the calls they wrap, which is large by design
+#![allow(clippy::result_large_err)]
+
+use std::{future::Future, time::Duration};
+use tokio::{
+    sync::mpsc::{error::SendTimeoutError, Sender},
+    time::timeout,
+};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{transport::Endpoint, Status};
+
+/// Limits on how long connecting, calls and streams may take.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    /// Deadline of a unary or server streaming call until its response
+    /// starts, set on each such request
+    pub request: Option<Duration>,
+    /// Longest time a stream may go without any message
+    pub idle: Option<Duration>,
+    pub keepalive_interval: Option<Duration>,
+    pub keepalive_timeout: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn apply(&self, mut endpoint: Endpoint) -> Endpoint {
+        if let Some(connect) = self.connect {
+            endpoint = endpoint.connect_timeout(connect);
+        }
+        if let Some(interval) = self.keepalive_interval {
+            endpoint = endpoint
+                .http2_keep_alive_interval(interval)
+                .keep_alive_while_idle(true);
+        }
+        if let Some(keepalive_timeout) = self.keepalive_timeout {
+            endpoint = endpoint.keep_alive_timeout(keepalive_timeout);
+        }
+
+        endpoint
+    }
+}
+
+/// Waits for response of a call to start, failing if the server sends
+/// nothing for `idle`.
+pub async fn response<T, F>(call: F, idle: Option<Duration>) -> Result<T, Status>
+where
+    F: Future<Output = Result<T, Status>>,
+{
+    match idle {
+        Some(idle) => timeout(idle, call)
+            .await
+            .unwrap_or_else(|_| Err(idle_status(idle))),
+        None => call.await,
+    }
+}
+
+/// Receives next message of a server stream, failing if the server sends
+/// nothing for `idle`. Such connection is likely dead, so the failure is
+/// reported as unavailable, which is retried.
+pub async fn next_message<S>(
+    stream: &mut S,
+    idle: Option<Duration>,
+) -> Result<Option<S::Item>, Status>
+where
+    S: Stream + Unpin,
+{
+    match idle {
+        Some(idle) => timeout(idle, stream.next())
+            .await
+            .map_err(|_| idle_status(idle)),
+        None => Ok(stream.next().await),
+    }
+}
+
+fn idle_status(idle: Duration) -> Status {
+    Status::unavailable(format!(
+        "No data received for {}",
+        humantime::format_duration(idle)
+    ))
+}
+
+/// Sends message to a client stream, failing if the server doesn't take it
+/// for `idle`.
+pub async fn send_message<T>(
+    tx: &Sender<T>,
+    message: T,
+    idle: Option<Duration>,
+) -> anyhow::Result<()> {
+    match idle {
+        Some(idle) => tx
+            .send_timeout(message, idle)
+            .await
+            .map_err(|err| match err {
+                SendTimeoutError::Timeout(_) => anyhow::anyhow!(
+                    "Server received nothing for {}",
+                    humantime::format_duration(idle)
+                ),
+                SendTimeoutError::Closed(_) => anyhow::anyhow!("Server closed the stream"),
+            }),
+        None => tx
+            .send(message)
+            .await
+            .map_err(|_| anyhow::anyhow!("Server closed the stream")),
+    }
+}
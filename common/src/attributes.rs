@@ -0,0 +1,111 @@
+use proto::api::{ExtendedAttribute, FileAttributes};
+use std::fs::{Metadata, Permissions};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::fs;
+use tracing::warn;
+
+/// Only permission bits are kept, without setuid, setgid and sticky bits.
+const MODE_MASK: u32 = 0o777;
+
+/// Attributes of a local file to be kept by the receiving side.
+pub fn from_metadata(metadata: &Metadata) -> FileAttributes {
+    let modified_ns = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_nanos() as u64);
+
+    FileAttributes {
+        modified_ns,
+        mode: metadata.permissions().mode() & MODE_MASK,
+    }
+}
+
+/// Sets permissions and modification time of a written file.
+pub async fn apply(path: &Path, attributes: &FileAttributes) -> io::Result<()> {
+    fs::set_permissions(path, Permissions::from_mode(attributes.mode & MODE_MASK)).await?;
+
+    let modified = UNIX_EPOCH + Duration::from_nanos(attributes.modified_ns);
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        filetime::set_file_mtime(path, filetime::FileTime::from_system_time(modified))
+    })
+    .await?
+}
+
+/// Namespaces of extended attributes which may be transferred, e.g. `user`
+/// for `user.*`. POSIX ACLs are kept in the `system` namespace.
+#[derive(Clone, Debug, Default)]
+pub struct XattrNamespaces(Vec<String>);
+
+impl XattrNamespaces {
+    pub fn new(namespaces: &[String]) -> Self {
+        Self(
+            namespaces
+                .iter()
+                .map(|namespace| namespace.trim_end_matches('.').to_string())
+                .collect(),
+        )
+    }
+
+    pub fn allows(&self, name: &str) -> bool {
+        self.0.iter().any(|namespace| {
+            matches!(name.strip_prefix(namespace.as_str()), Some(rest) if rest.starts_with('.'))
+        })
+    }
+}
+
+/// Extended attributes of a file in allowed namespaces.
+pub async fn read_xattrs(
+    path: &Path,
+    namespaces: &XattrNamespaces,
+) -> io::Result<Vec<ExtendedAttribute>> {
+    let path = path.to_path_buf();
+    let namespaces = namespaces.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut xattrs = Vec::new();
+
+        for name in xattr::list(&path)? {
+            let Some(name) = name.to_str().filter(|name| namespaces.allows(name)) else {
+                continue;
+            };
+            if let Some(value) = xattr::get(&path, name)? {
+                xattrs.push(ExtendedAttribute {
+                    name: name.to_string(),
+                    value,
+                });
+            }
+        }
+
+        Ok(xattrs)
+    })
+    .await?
+}
+
+/// Sets extended attributes of a written file, skipping those outside of
+/// allowed namespaces.
+pub async fn apply_xattrs(
+    path: &Path,
+    xattrs: Vec<ExtendedAttribute>,
+    namespaces: &XattrNamespaces,
+) -> io::Result<()> {
+    let path = path.to_path_buf();
+    let namespaces = namespaces.clone();
+
+    tokio::task::spawn_blocking(move || {
+        for xattr in xattrs {
+            if !namespaces.allows(&xattr.name) {
+                warn!("Skipped extended attribute {} of {:?}", xattr.name, path);
+                continue;
+            }
+            xattr::set(&path, &xattr.name, &xattr.value)?;
+        }
+
+        Ok(())
+    })
+    .await?
+}
@@ -0,0 +1,466 @@
+use crate::temp_file::TempFile;
+use proto::api::BlockSignature;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, SeekFrom};
+use std::path::Path;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+pub const MIN_BLOCK_SIZE: u32 = 2 * 1024;
+pub const MAX_BLOCK_SIZE: u32 = 16 * 1024 * 1024;
+/// Most signatures accepted from the receiver, which are kept in memory
+pub const MAX_BLOCK_COUNT: usize = 1024 * 1024;
+/// Strong checksums are SHA-256 truncated to this length, the whole file
+/// checksum catches their collisions
+const STRONG_HASH_LEN: usize = 16;
+/// New data is sent in pieces of at most this size
+const MAX_DATA_LEN: usize = 1024 * 1024;
+const CHANNEL_SIZE: usize = 10;
+
+/// Block size for signatures of a file, close to square root of its size
+/// like rsync does, so that neither signatures nor resent blocks get big.
+pub fn block_size(size: u64) -> u32 {
+    let sqrt = (size as f64).sqrt() as u64;
+    sqrt.max(size.div_ceil(MAX_BLOCK_COUNT as u64))
+        .next_multiple_of(1024)
+        .clamp(MIN_BLOCK_SIZE as u64, MAX_BLOCK_SIZE as u64) as u32
+}
+
+/// Checks block size requested by the other side of a transfer.
+pub fn check_block_size(block_size: u32) -> io::Result<()> {
+    if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Block size must be from {MIN_BLOCK_SIZE} to {MAX_BLOCK_SIZE} bytes"),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Weak checksum of rsync, which can be moved by a byte in constant time.
+#[derive(Clone, Copy, Debug, Default)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let mut rolling = Self {
+            len: block.len() as u32,
+            ..Default::default()
+        };
+        for (i, &byte) in block.iter().enumerate() {
+            rolling.a = rolling.a.wrapping_add(byte as u32);
+            rolling.b = rolling
+                .b
+                .wrapping_add(((block.len() - i) as u32).wrapping_mul(byte as u32));
+        }
+        rolling
+    }
+
+    /// Moves the window by one byte, dropping `out` and appending `in_`.
+    fn roll(&mut self, out: u8, in_: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(in_ as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.b << 16) | (self.a & 0xffff)
+    }
+}
+
+fn strong_hash(block: &[u8]) -> Vec<u8> {
+    Sha256::digest(block)[..STRONG_HASH_LEN].to_vec()
+}
+
+/// Signatures of consecutive blocks of the receiver's copy of a file.
+pub async fn signatures(path: &Path, block_size: u32) -> io::Result<Vec<BlockSignature>> {
+    let mut file = fs::File::open(path).await?;
+    let mut signatures = Vec::new();
+
+    loop {
+        let mut block = Vec::with_capacity(block_size as usize);
+        (&mut file)
+            .take(block_size as u64)
+            .read_to_end(&mut block)
+            .await?;
+        if block.is_empty() {
+            break;
+        }
+        if signatures.len() == MAX_BLOCK_COUNT {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "File has too many blocks for a delta transfer",
+            ));
+        }
+
+        signatures.push(BlockSignature {
+            weak: Rolling::new(&block).digest(),
+            strong: strong_hash(&block),
+        });
+    }
+
+    Ok(signatures)
+}
+
+/// SHA-256 of the whole file, as checked after delta transfers.
+pub async fn checksum(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut chunk = vec![0; MAX_DATA_LEN];
+
+    loop {
+        let n = file.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&chunk[..n]);
+    }
+
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Instruction to rebuild a file from blocks of the receiver's copy and new
+/// data.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeltaOp {
+    /// `count` blocks of the receiver's copy starting at block `index`
+    Copy {
+        index: u64,
+        count: u64,
+    },
+    Data(Vec<u8>),
+}
+
+/// Computes delta of `file` against signatures of the receiver's copy on a
+/// blocking thread. Returns its operations and the task, which resolves to
+/// SHA-256 of the whole file. Dropping the receiver stops the task.
+pub fn spawn_encoder(
+    file: std::fs::File,
+    block_size: u32,
+    signatures: Vec<BlockSignature>,
+) -> (mpsc::Receiver<DeltaOp>, JoinHandle<io::Result<Vec<u8>>>) {
+    let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+
+    let task = tokio::task::spawn_blocking(move || {
+        encode(file, block_size as usize, &signatures, |op| {
+            tx.blocking_send(op)
+                .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "Delta receiver is gone"))
+        })
+    });
+
+    (rx, task)
+}
+
+fn encode<R: Read>(
+    mut reader: R,
+    block_size: usize,
+    signatures: &[BlockSignature],
+    mut emit: impl FnMut(DeltaOp) -> io::Result<()>,
+) -> io::Result<Vec<u8>> {
+    let mut blocks: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (index, signature) in signatures.iter().enumerate() {
+        blocks.entry(signature.weak).or_default().push(index);
+    }
+
+    let mut hasher = Sha256::new();
+    let mut buffer = Vec::new();
+    let mut eof = false;
+    // Start of data not sent yet and of the compared window
+    let mut data_start = 0;
+    let mut position = 0;
+    let mut rolling = None;
+    let mut copy: Option<(u64, u64)> = None;
+
+    loop {
+        // Keep a whole window ahead, dropping what was sent already
+        if !eof && buffer.len() < position + block_size {
+            buffer.drain(..data_start);
+            position -= data_start;
+            data_start = 0;
+
+            let read = (&mut reader)
+                .take(MAX_DATA_LEN.max(block_size) as u64)
+                .read_to_end(&mut buffer)?;
+            hasher.update(&buffer[buffer.len() - read..]);
+            eof = read == 0;
+            continue;
+        }
+        if buffer.len() < position + block_size {
+            break;
+        }
+
+        let window = &buffer[position..position + block_size];
+        let weak = *rolling.get_or_insert_with(|| Rolling::new(window));
+        let matched = blocks.get(&weak.digest()).and_then(|indexes| {
+            let strong = strong_hash(window);
+            indexes
+                .iter()
+                .find(|&&index| signatures[index].strong == strong)
+        });
+
+        if let Some(&index) = matched {
+            if data_start < position {
+                flush_copy(&mut copy, &mut emit)?;
+                emit(DeltaOp::Data(buffer[data_start..position].to_vec()))?;
+            }
+            copy = match copy {
+                Some((start, count)) if start + count == index as u64 => Some((start, count + 1)),
+                mut previous => {
+                    flush_copy(&mut previous, &mut emit)?;
+                    Some((index as u64, 1))
+                }
+            };
+            position += block_size;
+            data_start = position;
+            rolling = None;
+        } else {
+            if position + block_size < buffer.len() {
+                if let Some(rolling) = &mut rolling {
+                    rolling.roll(buffer[position], buffer[position + block_size]);
+                }
+            } else {
+                rolling = None;
+            }
+            position += 1;
+
+            if position - data_start >= MAX_DATA_LEN {
+                flush_copy(&mut copy, &mut emit)?;
+                emit(DeltaOp::Data(buffer[data_start..position].to_vec()))?;
+                data_start = position;
+            }
+        }
+    }
+
+    flush_copy(&mut copy, &mut emit)?;
+    // Tail shorter than a block
+    for data in buffer[data_start..].chunks(MAX_DATA_LEN) {
+        emit(DeltaOp::Data(data.to_vec()))?;
+    }
+
+    Ok(hasher.finalize().to_vec())
+}
+
+fn flush_copy(
+    copy: &mut Option<(u64, u64)>,
+    emit: &mut impl FnMut(DeltaOp) -> io::Result<()>,
+) -> io::Result<()> {
+    if let Some((index, count)) = copy.take() {
+        emit(DeltaOp::Copy { index, count })?;
+    }
+
+    Ok(())
+}
+
+/// Rebuilds a file from delta operations into a temporary file next to the
+/// receiver's copy, which can replace it once the checksum matches. The
+/// temporary file is removed if the decoder is dropped before that.
+pub struct DeltaDecoder {
+    basis: fs::File,
+    basis_len: u64,
+    block_size: u64,
+    output: fs::File,
+    temp_file: TempFile,
+    hasher: Sha256,
+    written: u64,
+}
+
+impl DeltaDecoder {
+    pub async fn new(path: &Path, block_size: u32) -> io::Result<Self> {
+        check_block_size(block_size)?;
+        let basis = fs::File::open(path).await?;
+        let basis_len = basis.metadata().await?.len();
+        let (temp_file, output) = TempFile::create(path).await?;
+
+        Ok(Self {
+            basis,
+            basis_len,
+            block_size: block_size as u64,
+            output,
+            temp_file,
+            hasher: Sha256::new(),
+            written: 0,
+        })
+    }
+
+    /// Number of bytes written so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Number of bytes `op` adds to the file, checked before applying it.
+    pub fn op_len(&self, op: &DeltaOp) -> io::Result<u64> {
+        match op {
+            DeltaOp::Data(data) => Ok(data.len() as u64),
+            DeltaOp::Copy { index, count } => {
+                let start = index.saturating_mul(self.block_size);
+                let end = index
+                    .saturating_add(*count)
+                    .saturating_mul(self.block_size)
+                    .min(self.basis_len);
+                if *count == 0 || start >= end {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Blocks {index}+{count} are beyond end of file"),
+                    ));
+                }
+
+                Ok(end - start)
+            }
+        }
+    }
+
+    pub async fn apply(&mut self, op: DeltaOp) -> io::Result<()> {
+        let len = self.op_len(&op)?;
+
+        match op {
+            DeltaOp::Data(data) => {
+                self.hasher.update(&data);
+                self.output.write_all(&data).await?;
+            }
+            DeltaOp::Copy { index, .. } => {
+                self.basis
+                    .seek(SeekFrom::Start(index * self.block_size))
+                    .await?;
+                let mut left = len;
+                while left > 0 {
+                    let mut chunk = Vec::with_capacity(left.min(MAX_DATA_LEN as u64) as usize);
+                    (&mut self.basis)
+                        .take(left.min(MAX_DATA_LEN as u64))
+                        .read_to_end(&mut chunk)
+                        .await?;
+                    if chunk.is_empty() {
+                        return Err(io::Error::new(
+                            ErrorKind::UnexpectedEof,
+                            "File changed during delta transfer",
+                        ));
+                    }
+                    self.hasher.update(&chunk);
+                    self.output.write_all(&chunk).await?;
+                    left -= chunk.len() as u64;
+                }
+            }
+        }
+
+        self.written += len;
+
+        Ok(())
+    }
+
+    /// Returns the rebuilt file, ready to replace the receiver's copy, if it
+    /// matches `checksum` of the sender's file, otherwise removes it.
+    pub async fn finish(mut self, checksum: &[u8]) -> io::Result<TempFile> {
+        if self.hasher.finalize_reset().as_slice() != checksum {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Checksum of rebuilt file doesn't match",
+            ));
+        }
+
+        self.output.sync_all().await?;
+
+        Ok(self.temp_file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: usize = 4;
+
+    fn block_signatures(basis: &[u8]) -> Vec<BlockSignature> {
+        basis
+            .chunks(BLOCK_SIZE)
+            .map(|block| BlockSignature {
+                weak: Rolling::new(block).digest(),
+                strong: strong_hash(block),
+            })
+            .collect()
+    }
+
+    fn delta(basis: &[u8], data: &[u8]) -> Vec<DeltaOp> {
+        let mut ops = Vec::new();
+        let checksum = encode(data, BLOCK_SIZE, &block_signatures(basis), |op| {
+            ops.push(op);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(checksum, Sha256::digest(data).to_vec());
+        ops
+    }
+
+    fn rebuild(basis: &[u8], ops: &[DeltaOp]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for op in ops {
+            match op {
+                DeltaOp::Copy { index, count } => {
+                    let start = *index as usize * BLOCK_SIZE;
+                    let end = (start + *count as usize * BLOCK_SIZE).min(basis.len());
+                    data.extend_from_slice(&basis[start..end]);
+                }
+                DeltaOp::Data(new) => data.extend_from_slice(new),
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn rolled_checksum_matches_new() {
+        let data = b"the quick brown fox jumps over the lazy dog";
+        let mut rolling = Rolling::new(&data[..BLOCK_SIZE]);
+
+        for start in 1..=data.len() - BLOCK_SIZE {
+            rolling.roll(data[start - 1], data[start + BLOCK_SIZE - 1]);
+            let window = &data[start..start + BLOCK_SIZE];
+            assert_eq!(rolling.digest(), Rolling::new(window).digest());
+        }
+    }
+
+    #[test]
+    fn unchanged_blocks_are_copied() {
+        let basis = b"aaaabbbbccccdddd";
+
+        assert_eq!(delta(basis, basis), [DeltaOp::Copy { index: 0, count: 4 }]);
+
+        // Blocks are found after inserted and before changed data
+        let data = b"aaaaXbbbbccccddZd";
+        let ops = delta(basis, data);
+        assert_eq!(
+            ops,
+            [
+                DeltaOp::Copy { index: 0, count: 1 },
+                DeltaOp::Data(b"X".to_vec()),
+                DeltaOp::Copy { index: 1, count: 2 },
+                DeltaOp::Data(b"ddZd".to_vec()),
+            ]
+        );
+        assert_eq!(rebuild(basis, &ops), data);
+    }
+
+    #[test]
+    fn moved_blocks_are_copied() {
+        let basis = b"aaaabbbbccccdd";
+        let data = b"ccccaaaabbbbdd";
+        let ops = delta(basis, data);
+
+        assert_eq!(
+            ops,
+            [
+                DeltaOp::Copy { index: 2, count: 1 },
+                DeltaOp::Copy { index: 0, count: 2 },
+                DeltaOp::Data(b"dd".to_vec()),
+            ]
+        );
+        assert_eq!(rebuild(basis, &ops), data);
+    }
+}
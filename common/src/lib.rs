@@ -0,0 +1,6 @@
+pub mod attributes;
+pub mod delta;
+pub mod logging;
+pub mod rotating_file;
+pub mod sparse;
+pub mod temp_file;
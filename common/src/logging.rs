@@ -0,0 +1,118 @@
+use crate::rotating_file::RotatingFile;
+use anyhow::Result;
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize, Serializer};
+use std::{path::PathBuf, sync::Mutex};
+use tracing::Level;
+use tracing_subscriber::{
+    filter::LevelFilter, fmt::writer::BoxMakeWriter, prelude::*, EnvFilter, Layer,
+};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    #[default]
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+/// Where logs are written when there's no log file.
+#[derive(Clone, Copy, Debug)]
+pub enum Console {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Args)]
+pub struct LogArgs {
+    /// Default log level, overridden per module by RUST_LOG
+    #[arg(short, long, default_value = "info")]
+    pub verbose: Level,
+    #[arg(long, value_enum, default_value_t)]
+    pub log_format: LogFormat,
+    /// Write logs to this file instead of the console
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+    /// Size in bytes after which the log file is rotated
+    #[arg(long, default_value_t = LogConfig::DEFAULT_MAX_SIZE)]
+    pub log_file_max_size: u64,
+    /// Number of rotated log files to keep
+    #[arg(long, default_value_t = LogConfig::DEFAULT_MAX_FILES)]
+    pub log_file_max_files: usize,
+}
+
+impl LogArgs {
+    pub fn config(&self) -> LogConfig {
+        LogConfig {
+            level: self.verbose,
+            format: self.log_format,
+            path: self.log_file.clone(),
+            max_size: self.log_file_max_size,
+            max_files: self.log_file_max_files,
+        }
+    }
+}
+
+/// Effective logging settings.
+#[derive(Clone, Debug, Serialize)]
+pub struct LogConfig {
+    /// Default log level, overridden per module by RUST_LOG
+    #[serde(serialize_with = "serialize_level")]
+    pub level: Level,
+    pub format: LogFormat,
+    /// Log file, console if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    pub max_size: u64,
+    pub max_files: usize,
+}
+
+impl LogConfig {
+    pub const DEFAULT_LEVEL: Level = Level::INFO;
+    pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+    pub const DEFAULT_MAX_FILES: usize = 5;
+}
+
+fn serialize_level<S: Serializer>(level: &Level, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&level.as_str().to_lowercase())
+}
+
+pub fn init(config: &LogConfig, console: Console) -> Result<()> {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::from_level(config.level).into())
+        .from_env_lossy();
+
+    let writer = match &config.path {
+        Some(path) => BoxMakeWriter::new(Mutex::new(RotatingFile::new(
+            path,
+            config.max_size,
+            config.max_files,
+        )?)),
+        None => match console {
+            Console::Stdout => BoxMakeWriter::new(std::io::stdout),
+            Console::Stderr => BoxMakeWriter::new(std::io::stderr),
+        },
+    };
+
+    let layer = tracing_subscriber::fmt::layer()
+        .with_file(true)
+        .with_line_number(true)
+        .with_thread_ids(true)
+        .with_ansi(config.path.is_none())
+        .with_writer(writer);
+
+    let layer = match config.format {
+        LogFormat::Full => layer.with_target(false).boxed(),
+        LogFormat::Compact => layer.with_target(false).compact().boxed(),
+        LogFormat::Pretty => layer.with_target(false).pretty().boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(layer.with_filter(filter))
+        .init();
+
+    Ok(())
+}
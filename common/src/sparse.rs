@@ -0,0 +1,180 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+/// Part of a file, holes read as zeros but take no disk space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Extent {
+    Data { offset: u64, len: u64 },
+    Hole { len: u64 },
+}
+
+/// Data and holes of a file from `offset` up to `size`, found with
+/// `SEEK_DATA` and `SEEK_HOLE`. Filesystems without hole support report the
+/// whole file as data.
+pub async fn extents(file: &fs::File, offset: u64, size: u64) -> io::Result<Vec<Extent>> {
+    // Moves position of the shared file description, readers seek anyway
+    let file = file.try_clone().await?.into_std().await;
+
+    tokio::task::spawn_blocking(move || {
+        let mut extents = Vec::new();
+        let mut position = offset;
+
+        while position < size {
+            let data = match seek(&file, position, libc::SEEK_DATA) {
+                Ok(data) => data.min(size),
+                // Only a hole up to the end of file is left
+                Err(err) if err.raw_os_error() == Some(libc::ENXIO) => size,
+                Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+                    extents.push(Extent::Data {
+                        offset: position,
+                        len: size - position,
+                    });
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
+            if data > position {
+                extents.push(Extent::Hole {
+                    len: data - position,
+                });
+            }
+            if data == size {
+                break;
+            }
+
+            let hole = seek(&file, data, libc::SEEK_HOLE)?.min(size);
+            extents.push(Extent::Data {
+                offset: data,
+                len: hole - data,
+            });
+            position = hole;
+        }
+
+        Ok(extents)
+    })
+    .await?
+}
+
+fn seek(file: &std::fs::File, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+    let offset = libc::off_t::try_from(offset)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Offset is too large"))?;
+
+    // SAFETY: the descriptor is owned by `file`, which outlives the call
+    match unsafe { libc::lseek(file.as_raw_fd(), offset, whence) } {
+        -1 => Err(io::Error::last_os_error()),
+        position => Ok(position as u64),
+    }
+}
+
+/// Piece of a file read by [`SparseReader`].
+#[derive(Debug)]
+pub enum Piece {
+    Data(Vec<u8>),
+    Hole(u64),
+}
+
+/// Reads a file in chunks of data, with holes reported by length instead
+/// of being read as zeros.
+pub struct SparseReader {
+    file: fs::File,
+    extents: VecDeque<Extent>,
+}
+
+impl SparseReader {
+    pub async fn new(file: fs::File, offset: u64, size: u64) -> io::Result<Self> {
+        let extents = extents(&file, offset, size).await?.into();
+
+        Ok(Self { file, extents })
+    }
+
+    /// Next piece of at most `chunk_size` bytes of data, or a whole hole.
+    pub async fn next(&mut self, chunk_size: u64) -> io::Result<Option<Piece>> {
+        loop {
+            let Some(extent) = self.extents.pop_front() else {
+                return Ok(None);
+            };
+
+            match extent {
+                Extent::Hole { len } => return Ok(Some(Piece::Hole(len))),
+                Extent::Data { offset, len } => {
+                    let n = len.min(chunk_size);
+                    self.file.seek(SeekFrom::Start(offset)).await?;
+
+                    let mut chunk = Vec::with_capacity(n as usize);
+                    (&mut self.file).take(n).read_to_end(&mut chunk).await?;
+                    // File was truncated while being read
+                    if chunk.is_empty() {
+                        continue;
+                    }
+
+                    let read = chunk.len() as u64;
+                    if read < len {
+                        self.extents.push_front(Extent::Data {
+                            offset: offset + read,
+                            len: len - read,
+                        });
+                    }
+
+                    return Ok(Some(Piece::Data(chunk)));
+                }
+            }
+        }
+    }
+}
+
+/// Output of a transfer which can leave holes instead of writing zeros.
+pub trait HoleWrite: AsyncWrite + Unpin + Send {
+    fn write_hole(&mut self, len: u64) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+impl HoleWrite for fs::File {
+    /// Skips over the hole, [`finish_holes`] sets the size of the file if it
+    /// ends with one.
+    async fn write_hole(&mut self, len: u64) -> io::Result<()> {
+        let len = i64::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Hole is too large"))?;
+        self.flush().await?;
+        self.seek(SeekFrom::Current(len)).await?;
+
+        Ok(())
+    }
+}
+
+impl HoleWrite for tokio::io::Stdout {
+    async fn write_hole(&mut self, len: u64) -> io::Result<()> {
+        write_zeros(self, len).await
+    }
+}
+
+/// Writes `len` zeros to outputs which can't seek.
+pub async fn write_zeros<W>(output: &mut W, len: u64) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(ZEROS.len() as u64);
+        output.write_all(&ZEROS[..n as usize]).await?;
+        left -= n;
+    }
+
+    Ok(())
+}
+
+/// Extends file written with [`HoleWrite`] to its current position, which
+/// is past its end if it ends with a hole.
+pub async fn finish_holes(file: &mut fs::File) -> io::Result<()> {
+    file.flush().await?;
+    let position = file.stream_position().await?;
+    if file.metadata().await?.len() < position {
+        file.set_len(position).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::ffi::{OsStr, OsString};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs;
+use tracing::{debug, warn};
+
+/// Suffix of temporary files, which listings leave out.
+const SUFFIX: &str = ".ft-part";
+
+/// File written under a unique hidden name next to its target, which it
+/// replaces only once complete. It's removed if dropped before that, so a
+/// failed transfer leaves the previous version of the target in place.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    target: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    /// Creates a new temporary file for `target`, which may already exist.
+    /// Then the temporary file takes over its mode, owner and extended
+    /// attributes, as far as permitted, like overwriting it in place would.
+    pub async fn create(target: &Path) -> io::Result<(Self, fs::File)> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        loop {
+            let mut name = OsString::from(".");
+            name.push(target.file_name().unwrap_or_default());
+            name.push(format!(
+                ".{}-{}{SUFFIX}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            let path = target.with_file_name(name);
+
+            match fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .await
+            {
+                Ok(file) => {
+                    let temp_file = Self {
+                        path,
+                        target: target.to_path_buf(),
+                        persisted: false,
+                    };
+                    temp_file.copy_target_metadata().await?;
+                    return Ok((temp_file, file));
+                }
+                // Left behind by a crashed process with the same id
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn copy_target_metadata(&self) -> io::Result<()> {
+        let metadata = match fs::metadata(&self.target).await {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let path = self.path.clone();
+        let target = self.target.clone();
+
+        tokio::task::spawn_blocking(move || {
+            // Only root may give files away, others keep their own
+            if let Err(err) =
+                std::os::unix::fs::chown(&path, Some(metadata.uid()), Some(metadata.gid()))
+            {
+                debug!(%err, "Failed to take over owner of {:?}", target);
+            }
+            match xattr::list(&target) {
+                Ok(names) => {
+                    for name in names {
+                        if let Some(value) = xattr::get(&target, &name)? {
+                            if let Err(err) = xattr::set(&path, &name, &value) {
+                                debug!(%err, "Failed to take over {:?} of {:?}", name, target);
+                            }
+                        }
+                    }
+                }
+                Err(err) => debug!(%err, "Failed to list extended attributes of {:?}", target),
+            }
+            // Last, as it could make the file read-only
+            std::fs::set_permissions(&path, metadata.permissions())
+        })
+        .await?
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replaces the target with the complete file.
+    pub async fn persist(mut self) -> io::Result<()> {
+        fs::rename(&self.path, &self.target).await?;
+        self.persisted = true;
+
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            if let Err(err) = std::fs::remove_file(&self.path) {
+                warn!(%err, "Failed to remove temporary file {:?}", self.path);
+            }
+        }
+    }
+}
+
+/// Whether the file name is one of a [`TempFile`], which is hidden from
+/// listings.
+pub fn is_temp(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name.starts_with('.') && name.ends_with(SUFFIX)
+}
@@ -1,13 +1,13 @@
 use crate::{
     e2e_test_context::{AppType, E2ETestContext},
-    utils::get_base_client_cmd,
+    utils::{compare_files, get_base_client_cmd},
 };
 use assert_cmd::Command;
 use bytesize::ByteSize;
 use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
 use predicates::prelude::predicate;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 mod e2e_test_context;
 mod utils;
@@ -23,28 +23,13 @@ fn list_files(cmd: &mut Command) {
 fn download_file(cmd: &mut Command, files: (&PathBuf, &PathBuf)) {
     cmd.assert().success();
 
-    compare_files_on_disk(files.0, files.1);
+    assert!(compare_files(files.0, files.1));
 }
 
 fn upload_file(cmd: &mut Command, files: (&PathBuf, &PathBuf)) {
     cmd.assert().success();
 
-    compare_files_on_disk(files.0, files.1);
-}
-
-fn compare_files_on_disk(left: &Path, right: &Path) {
-    let status = std::process::Command::new("diff")
-        .arg(left.as_os_str())
-        .arg(right.as_os_str())
-        .stdin(std::process::Stdio::null())
-        .stdout(std::process::Stdio::null())
-        .stderr(std::process::Stdio::null())
-        .status()
-        .expect("failed to execute diff process");
-
-    if !status.success() {
-        panic!("diff for file failed");
-    }
+    assert!(compare_files(files.0, files.1));
 }
 
 fn criterion_benchmark_list_files(c: &mut Criterion) {
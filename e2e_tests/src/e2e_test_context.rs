@@ -32,7 +32,6 @@ pub struct Server {
 }
 
 pub struct TestFile {
-    pub handle: File,
     pub abs_path: PathBuf,
 }
 
@@ -135,13 +134,29 @@ impl E2ETestContext {
     const KEY_NAME: &str = "key.pem";
 
     pub fn start_server(&mut self, server_ip_address: IpAddr, tls: bool) {
+        self.start_server_with_args(server_ip_address, tls, &[]);
+    }
+
+    pub fn start_server_with_args(&mut self, server_ip_address: IpAddr, tls: bool, args: &[&str]) {
+        self.start_server_with_envs(server_ip_address, tls, args, &[]);
+    }
+
+    pub fn start_server_with_envs(
+        &mut self,
+        server_ip_address: IpAddr,
+        tls: bool,
+        args: &[&str],
+        envs: &[(&str, &str)],
+    ) {
         let server_bin_path = cargo_bin(Self::SERVER_BIN_NAME);
 
         let mut server_cmd = Command::new(server_bin_path);
         server_cmd
             .args(["--port", &self.port.to_string()])
             .args(["--address", &server_ip_address.to_string()])
-            .args(["--directory", self.server.dir.path().to_str().unwrap()]);
+            .args(["--directory", self.server.dir.path().to_str().unwrap()])
+            .args(args)
+            .envs(envs.iter().copied());
 
         if tls {
             server_cmd
@@ -237,7 +252,6 @@ impl E2ETestContext {
         file_handle.sync_all().expect("sync_all failed");
 
         let test_file = TestFile {
-            handle: file_handle,
             abs_path: file_path,
         };
         match app_type {
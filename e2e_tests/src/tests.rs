@@ -2,10 +2,15 @@ use crate::{
     e2e_test_context::{ctx, AppType, E2ETestContext},
     utils::{compare_files, get_base_client_cmd},
 };
-use predicates::prelude::{predicate, PredicateStrExt};
+use assert_cmd::{cargo::cargo_bin, Command};
+use predicates::prelude::{predicate, PredicateBooleanExt, PredicateStrExt};
 use rstest::rstest;
+use std::fs;
 use std::net::IpAddr;
+use std::os::unix::net::UnixDatagram;
 use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
 
 mod e2e_test_context;
 mod utils;
@@ -95,6 +100,35 @@ fn test_download_file_success(
     ));
 }
 
+#[rstest]
+fn test_download_keeps_existing_failure(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server(ip_address, false);
+    ctx.create_test_file(AppType::Client, "abc", "hello");
+
+    let local = &ctx.client.files[0].abs_path;
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download")
+        .args(["--file", "abc"])
+        .arg(local)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Failed to send file"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download-archive")
+        .args(["--remote", "missing"])
+        .arg(local)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("NotFound"));
+
+    // The previous file stays and no partial one is left behind
+    assert_eq!(fs::read_to_string(local).unwrap(), "hello");
+    assert_eq!(fs::read_dir(ctx.client.dir.path()).unwrap().count(), 1);
+}
+
 #[rstest]
 #[case::ipv4_non_tls("0.0.0.0", false)]
 #[case::ipv6_non_tls("::1", false)]
@@ -133,3 +167,1660 @@ fn test_upload_file_success(
         &ctx.client.files[0].abs_path,
     ));
 }
+
+#[rstest]
+fn test_json_log_file_success(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "::1".parse().unwrap();
+    ctx.start_server(ip_address, false);
+
+    let mut log_file_path = PathBuf::new();
+    log_file_path.push(ctx.client.dir.path());
+    log_file_path.push("client.log");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.args(["--verbose", "debug"])
+        .args(["--log-format", "json"])
+        .args(["--log-file", log_file_path.to_str().unwrap()])
+        .arg("list")
+        .assert()
+        .success();
+
+    let logs = fs::read_to_string(&log_file_path).unwrap();
+
+    assert!(!logs.is_empty());
+    assert!(logs
+        .lines()
+        .all(|line| line.starts_with('{') && line.ends_with('}')));
+}
+
+#[rstest]
+#[case::non_tls(false)]
+#[case::tls(true)]
+fn test_audit_log_success(mut ctx: E2ETestContext, #[case] tls: bool) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    if tls {
+        ctx.gen_all_creds();
+    }
+
+    let mut audit_log_path = PathBuf::new();
+    audit_log_path.push(ctx.creds_dir.path());
+    audit_log_path.push("audit.log");
+
+    ctx.start_server_with_args(
+        ip_address,
+        tls,
+        &["--audit-log", audit_log_path.to_str().unwrap()],
+    );
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, tls);
+    cmd.arg("download")
+        .args(["--file", "abc"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, tls);
+    cmd.arg("download")
+        .args(["--file", "missing"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .failure();
+
+    let audit_log = fs::read_to_string(&audit_log_path).unwrap();
+    let entries: Vec<&str> = audit_log.lines().collect();
+
+    assert_eq!(entries.len(), 2);
+    assert!(entries[0].contains(r#""operation":"download""#));
+    assert!(entries[0].contains(r#""path":"default:abc""#));
+    assert!(entries[0].contains(r#""bytes":5"#));
+    assert!(entries[0].contains(r#""result":"ok""#));
+    assert!(entries[1].contains(r#""path":"default:missing""#));
+    assert!(entries[1].contains(r#""result":"error""#));
+    assert_eq!(entries[0].contains("CN="), tls);
+}
+
+#[rstest]
+fn test_config_file_success(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+
+    let mut audit_log_path = PathBuf::new();
+    audit_log_path.push(ctx.creds_dir.path());
+    audit_log_path.push("audit.log");
+
+    let mut log_path = PathBuf::new();
+    log_path.push(ctx.creds_dir.path());
+    log_path.push("server.log");
+
+    let mut config_path = PathBuf::new();
+    config_path.push(ctx.creds_dir.path());
+    config_path.push("server.toml");
+    fs::write(
+        &config_path,
+        format!(
+            "directory = \"/nonexistent\"\ninsecure = true\n\n[log]\nlevel = \"debug\"\nformat = \"json\"\npath = {:?}\n\n[audit_log]\npath = {:?}\n",
+            log_path, audit_log_path
+        ),
+    )
+    .unwrap();
+
+    ctx.start_server_with_args(
+        ip_address,
+        false,
+        &["--config", config_path.to_str().unwrap()],
+    );
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("abc        5B"));
+
+    let audit_log = fs::read_to_string(&audit_log_path).unwrap();
+    assert!(audit_log.contains(r#""operation":"list""#));
+
+    let logs = fs::read_to_string(&log_path).unwrap();
+    assert!(!logs.is_empty());
+    assert!(logs
+        .lines()
+        .all(|line| line.starts_with('{') && line.ends_with('}')));
+}
+
+#[rstest]
+fn test_print_config_success(ctx: E2ETestContext) {
+    let mut config_path = PathBuf::new();
+    config_path.push(ctx.creds_dir.path());
+    config_path.push("server.toml");
+    fs::write(
+        &config_path,
+        "port = 1000\ninsecure = true\n\n[log]\nlevel = \"warn\"\nformat = \"json\"\n",
+    )
+    .unwrap();
+
+    Command::cargo_bin("server")
+        .unwrap()
+        .env("FT_SERVER_PORT", "2000")
+        .env("FT_SERVER_LOG_LEVEL", "debug")
+        .env("FT_SERVER_DIRECTORY", ctx.server.dir.path())
+        .args(["--config", config_path.to_str().unwrap()])
+        .args(["--address", "::1"])
+        .arg("--print-config")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("address = \"::1\""))
+        .stdout(predicate::str::contains("port = 2000"))
+        .stdout(predicate::str::contains("insecure = true"))
+        .stdout(predicate::str::contains("level = \"debug\""))
+        .stdout(predicate::str::contains("format = \"json\""));
+}
+
+#[rstest]
+fn test_print_config_flags_success(ctx: E2ETestContext) {
+    let mut config_path = PathBuf::new();
+    config_path.push(ctx.creds_dir.path());
+    config_path.push("server.toml");
+    fs::write(
+        &config_path,
+        "port = 1000\ninsecure = true\nxattrs = true\n",
+    )
+    .unwrap();
+
+    // Flags set to false override the configuration file and environment
+    Command::cargo_bin("server")
+        .unwrap()
+        .env("FT_SERVER_XATTRS", "true")
+        .env("FT_SERVER_DIRECTORY", ctx.server.dir.path())
+        .args(["--config", config_path.to_str().unwrap()])
+        .arg("--xattrs=false")
+        .arg("--print-config")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("xattrs = false"));
+
+    Command::cargo_bin("server")
+        .unwrap()
+        .env("FT_SERVER_DIRECTORY", ctx.server.dir.path())
+        .args(["--config", config_path.to_str().unwrap()])
+        .arg("--insecure=false")
+        .arg("--print-config")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("missing `cert`"));
+
+    Command::cargo_bin("server")
+        .unwrap()
+        .env("FT_SERVER_DIRECTORY", ctx.server.dir.path())
+        .args(["--cert", "server.pem", "--insecure"])
+        .arg("--print-config")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--insecure conflicts with --cert"));
+}
+
+#[rstest]
+fn test_config_file_invalid_failure(ctx: E2ETestContext) {
+    let mut config_path = PathBuf::new();
+    config_path.push(ctx.creds_dir.path());
+    config_path.push("server.toml");
+    fs::write(&config_path, "prot = 1000\n").unwrap();
+
+    Command::cargo_bin("server")
+        .unwrap()
+        .args(["--config", config_path.to_str().unwrap()])
+        .args(["--directory", ctx.server.dir.path().to_str().unwrap()])
+        .arg("--insecure")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("unknown field `prot`"));
+}
+
+#[rstest]
+#[case::non_tls(false)]
+#[case::tls(true)]
+fn test_client_profile_success(mut ctx: E2ETestContext, #[case] tls: bool) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    if tls {
+        ctx.gen_all_creds();
+    }
+    ctx.start_server_with_args(ip_address, tls, &["--token", "secret"]);
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+
+    let mut profile = format!("[profiles.test]\nport = {}\ntoken = \"secret\"\n", ctx.port);
+    if tls {
+        let server_creds = ctx.server.creds.as_ref().unwrap();
+        let client_creds = ctx.client.creds.as_ref().unwrap();
+        profile.push_str(&format!(
+            "address = \"localhost\"\nca_cert = {:?}\ncert = {:?}\nkey = {:?}\n",
+            server_creds.ca_cert, client_creds.identity.cert, client_creds.identity.key,
+        ));
+    } else {
+        profile.push_str("address = \"127.0.0.1\"\ninsecure = true\n");
+    }
+    profile.push_str(&format!("directory = {:?}\n", ctx.client.dir.path()));
+
+    let mut config_dir = PathBuf::new();
+    config_dir.push(ctx.creds_dir.path());
+    config_dir.push("grpc-file-transfer");
+    fs::create_dir_all(&config_dir).unwrap();
+    fs::write(config_dir.join("config.toml"), profile).unwrap();
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", ctx.creds_dir.path())
+        .env("FT_PROFILE", "test")
+        .arg("download")
+        .args(["--file", "abc"])
+        .assert()
+        .success();
+
+    let mut expected_client_file_path = PathBuf::new();
+    expected_client_file_path.push(ctx.client.dir.path());
+    expected_client_file_path.push("abc");
+
+    assert!(compare_files(
+        &expected_client_file_path,
+        &ctx.server.files[0].abs_path
+    ));
+}
+
+#[rstest]
+fn test_token_failure(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server_with_args(ip_address, false, &["--token", "secret,other"]);
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+
+    for token in [None, Some("wrong"), Some("secre")] {
+        let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+        if let Some(token) = token {
+            cmd.args(["--token", token]);
+        }
+        cmd.args(["--retries", "0"])
+            .arg("list")
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("Missing or invalid token"));
+    }
+
+    for token in ["secret", "other"] {
+        let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+        cmd.env("FT_TOKEN", token)
+            .arg("list")
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("abc"));
+    }
+}
+
+#[rstest]
+fn test_client_profile_not_found_failure(ctx: E2ETestContext) {
+    let mut config_path = PathBuf::new();
+    config_path.push(ctx.creds_dir.path());
+    config_path.push("config.toml");
+    fs::write(&config_path, "[profiles.prod]\nport = 1\n").unwrap();
+
+    Command::cargo_bin("client")
+        .unwrap()
+        .args(["--config-file", config_path.to_str().unwrap()])
+        .args(["--profile", "dev"])
+        .arg("list")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("profile `dev` not found"));
+}
+
+#[rstest]
+fn test_shares_success(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+
+    let share_dir = ctx.server.dir.path().join("docs");
+    fs::create_dir(&share_dir).unwrap();
+    fs::write(share_dir.join("report"), "hello").unwrap();
+
+    let share_arg = format!("docs={}", share_dir.to_str().unwrap());
+    ctx.start_server_with_args(ip_address, false, &["--share", &share_arg]);
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("shares")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("default     read-write  -"))
+        .stdout(predicate::str::contains("docs        read-write  -"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("list")
+        .args(["--share", "docs"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("report     5B"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download")
+        .args(["--file", "docs:report"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    assert!(compare_files(
+        &ctx.client.dir.path().join("report"),
+        &share_dir.join("report")
+    ));
+
+    ctx.create_test_file(AppType::Client, "abc", "grpc");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "docs:abc"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    assert!(compare_files(
+        &share_dir.join("abc"),
+        &ctx.client.files[0].abs_path
+    ));
+}
+
+#[rstest]
+fn test_share_options_failure(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.gen_all_creds();
+
+    let mut config = String::new();
+    for share in ["ro", "small", "private"] {
+        let share_dir = ctx.server.dir.path().join(share);
+        fs::create_dir(&share_dir).unwrap();
+        config.push_str(&format!("[shares.{share}]\ndirectory = {share_dir:?}\n"));
+        match share {
+            "ro" => config.push_str("mode = \"read-only\"\n"),
+            "small" => config.push_str("quota = 3\n"),
+            _ => config.push_str("acl = [\"CN=nobody\"]\n"),
+        }
+    }
+
+    let config_path = ctx.creds_dir.path().join("server.toml");
+    fs::write(&config_path, config).unwrap();
+
+    ctx.start_server_with_args(
+        ip_address,
+        true,
+        &["--config", config_path.to_str().unwrap()],
+    );
+    ctx.create_test_file(AppType::Client, "abc", "hello");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, true);
+    cmd.arg("shares")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("ro          read-only   -"))
+        .stdout(predicate::str::contains("small       read-write  3B"))
+        .stdout(predicate::str::contains("private").not());
+
+    for (file, error) in [
+        ("ro:abc", "read-only"),
+        ("small:abc", "quota exceeded"),
+        ("private:abc", "denied"),
+        ("missing:abc", "not found"),
+    ] {
+        let mut cmd = get_base_client_cmd(&ctx, &ip_address, true);
+        cmd.arg("upload")
+            .args(["--file", file])
+            .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(error));
+    }
+
+    assert!(!ctx.server.dir.path().join("small").join("abc").exists());
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, true);
+    cmd.arg("download")
+        .args(["--file", "ro:../small/abc"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid file name"));
+
+    // Files in subdirectories count towards the quota too
+    let nested = ctx.server.dir.path().join("small").join("sub");
+    fs::create_dir(&nested).unwrap();
+    fs::write(nested.join("data"), "abc").unwrap();
+    ctx.create_test_file(AppType::Client, "one", "x");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, true);
+    cmd.arg("upload")
+        .args(["--file", "small:one"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("quota exceeded"));
+}
+
+#[rstest]
+fn test_write_only_mode_success(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+
+    let share_dir = ctx.server.dir.path().join("public");
+    fs::create_dir(&share_dir).unwrap();
+
+    let config_path = ctx.creds_dir.path().join("server.toml");
+    fs::write(
+        &config_path,
+        format!("[shares.public]\ndirectory = {share_dir:?}\nmode = \"read-only\"\n"),
+    )
+    .unwrap();
+
+    ctx.start_server_with_args(
+        ip_address,
+        false,
+        &[
+            "--mode",
+            "write-only",
+            "--config",
+            config_path.to_str().unwrap(),
+        ],
+    );
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+    ctx.create_test_file(AppType::Client, "xyz", "grpc");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("capabilities")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("mode        write-only"))
+        .stdout(predicate::str::contains("list        denied"))
+        .stdout(predicate::str::contains("download    denied"))
+        .stdout(predicate::str::contains("upload      allowed"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("capabilities")
+        .args(["--share", "public"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("mode        read-only"))
+        .stdout(predicate::str::contains("upload      denied"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("list")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("PermissionDenied"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download")
+        .args(["--file", "abc"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("PermissionDenied"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "xyz"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    // Signatures can't be read, so the delta upload falls back to a whole one
+    fs::write(ctx.server.dir.path().join("xyz"), "old").unwrap();
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "xyz", "--delta"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "public:xyz"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("PermissionDenied"));
+
+    assert!(compare_files(
+        &ctx.server.dir.path().join("xyz"),
+        &ctx.client.files[0].abs_path
+    ));
+}
+
+#[rstest]
+fn test_max_file_size_failure(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+
+    ctx.start_server_with_args(ip_address, false, &["--max-file-size", "3"]);
+    ctx.create_test_file(AppType::Client, "abc", "hello");
+    ctx.create_test_file(AppType::Client, "xyz", "hi");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "abc"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("maximum size of 3 bytes"));
+
+    assert!(!ctx.server.dir.path().join("abc").exists());
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "xyz"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    // Without declared size the limit is hit mid-upload, the previous
+    // version must survive
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "xyz", "-"])
+        .write_stdin("hello world")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("maximum size of 3 bytes"));
+
+    assert_eq!(fs::read(ctx.server.dir.path().join("xyz")).unwrap(), b"hi");
+    assert_eq!(fs::read_dir(ctx.server.dir.path()).unwrap().count(), 1);
+}
+
+#[rstest]
+fn test_min_free_space_failure(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    let min_free_space = u64::MAX.to_string();
+
+    ctx.start_server_with_args(ip_address, false, &["--min-free-space", &min_free_space]);
+    ctx.create_test_file(AppType::Client, "abc", "hello");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "abc"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Not enough free disk space"));
+
+    assert!(!ctx.server.dir.path().join("abc").exists());
+}
+
+#[rstest]
+fn test_rate_limit_failure(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+
+    ctx.start_server_with_args(
+        ip_address,
+        false,
+        &["--max-requests-per-second-per-peer", "1"],
+    );
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("list").assert().success();
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.args(["--retries", "0"])
+        .arg("list")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Too many requests"))
+        .stderr(predicate::str::contains("retry-after"));
+
+    // Rate limited requests are retried after the requested delay
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("list")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("retrying in"));
+}
+
+#[rstest]
+fn test_unix_socket_success(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    let socket_address = format!(
+        "unix:{}",
+        ctx.creds_dir.path().join("ft.sock").to_str().unwrap()
+    );
+
+    ctx.start_server_with_args(ip_address, false, &["--listen", &socket_address]);
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+    ctx.create_test_file(AppType::Client, "xyz", "grpc");
+
+    let mut cmd = Command::cargo_bin("client").unwrap();
+    cmd.args(["--address", &socket_address])
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("abc        5B"));
+
+    let mut cmd = Command::cargo_bin("client").unwrap();
+    cmd.args(["--address", &socket_address])
+        .arg("upload")
+        .args(["--file", "xyz"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    assert!(ctx.server.dir.path().join("xyz").exists());
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("xyz        4B"));
+}
+
+#[rstest]
+fn test_multiple_listeners_success(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.gen_all_creds();
+
+    let loopback_port = portpicker::pick_unused_port().unwrap().to_string();
+    let ipv6_port = portpicker::pick_unused_port().unwrap().to_string();
+
+    ctx.start_server_with_args(
+        ip_address,
+        true,
+        &[
+            "--listen",
+            &format!("http://127.0.0.1:{loopback_port}"),
+            "--listen",
+            &format!("http://[::1]:{ipv6_port}"),
+        ],
+    );
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, true);
+    cmd.arg("list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("abc        5B"));
+
+    for (address, port) in [("127.0.0.1", &loopback_port), ("::1", &ipv6_port)] {
+        let mut cmd = Command::cargo_bin("client").unwrap();
+        cmd.args(["--address", address])
+            .args(["--port", port])
+            .arg("--insecure")
+            .arg("list")
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("abc        5B"));
+    }
+}
+
+#[rstest]
+fn test_notify_socket_success(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+
+    let notify_socket_path = ctx.creds_dir.path().join("notify.sock");
+    let notify_socket = UnixDatagram::bind(&notify_socket_path).unwrap();
+    notify_socket
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+
+    ctx.start_server_with_envs(
+        ip_address,
+        false,
+        &[],
+        &[("NOTIFY_SOCKET", notify_socket_path.to_str().unwrap())],
+    );
+
+    let mut buf = [0; 64];
+    let n = notify_socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"READY=1");
+
+    let pid = ctx.server.process.as_ref().unwrap().id();
+    Command::new("kill").arg(pid.to_string()).assert().success();
+
+    let n = notify_socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"STOPPING=1");
+}
+
+#[rstest]
+fn test_list_files_output_success(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server(ip_address, false);
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+    ctx.create_test_file(AppType::Server, "x,y", "grpc");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("list")
+        .args(["--output", "json"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(r#""name": "abc""#))
+        .stdout(predicate::str::contains(r#""size": 5"#))
+        .stdout(predicate::str::contains(r#""modified": "#));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("list")
+        .args(["--output", "csv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("name,size,modified\n"))
+        .stdout(predicate::str::contains("\nabc,5,"))
+        .stdout(predicate::str::contains("\n\"x,y\",4,"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("list")
+        .args(["--output", "tsv"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\nabc\t5\t"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("list")
+        .arg("--bytes")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("abc        5 "));
+}
+
+#[rstest]
+fn test_list_files_filter_success(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server(ip_address, false);
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+    ctx.create_test_file(AppType::Server, "xyz", "grpc");
+    ctx.create_test_file(AppType::Server, "a.log", "0123456789");
+
+    for (args, expected) in [
+        (vec!["--glob", "*.log"], "name,size,modified\na.log,10,"),
+        (vec!["--regex", "^x"], "name,size,modified\nxyz,4,"),
+        (
+            vec!["--min-size", "5", "--sort", "size", "--reverse"],
+            "name,size,modified\na.log,10,",
+        ),
+        (
+            vec!["--sort", "name", "--limit", "2"],
+            "name,size,modified\na.log,10,",
+        ),
+        (
+            vec!["--modified-since", "2999-01-01T00:00:00Z"],
+            "name,size,modified",
+        ),
+    ] {
+        let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+        let output = cmd
+            .arg("list")
+            .args(["--output", "csv"])
+            .args(args)
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+
+        assert!(output.status.success());
+        assert!(stdout.starts_with(expected), "{stdout}");
+    }
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    let output = cmd
+        .arg("list")
+        .args(["--output", "csv", "--sort", "name", "--limit", "2"])
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap().lines().count(), 3);
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("list")
+        .args(["--regex", "["])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid regex"));
+}
+
+#[rstest]
+fn test_list_files_pages_success(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server(ip_address, false);
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+    ctx.create_test_file(AppType::Server, "xyz", "grpc");
+    ctx.create_test_file(AppType::Server, "a.log", "0123456789");
+
+    for (args, expected) in [
+        (vec!["--page-size", "2"], vec!["a.log", "abc", "xyz"]),
+        (vec!["--page-size", "1"], vec!["a.log", "abc", "xyz"]),
+        (
+            vec!["--page-size", "2", "--sort", "size", "--reverse"],
+            vec!["a.log", "abc", "xyz"],
+        ),
+        (
+            vec!["--page-size", "1", "--sort", "size"],
+            vec!["xyz", "abc", "a.log"],
+        ),
+    ] {
+        let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+        let output = cmd
+            .arg("list")
+            .args(["--output", "csv"])
+            .args(args)
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let names: Vec<_> = stdout
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap())
+            .collect();
+
+        assert!(output.status.success());
+        assert_eq!(names, expected, "{stdout}");
+    }
+
+    // Pages make up a single table, CSV document or JSON array
+    for format in ["table", "json", "csv"] {
+        let mut outputs = ["--limit", "--page-size"].map(|arg| {
+            let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+            let output = cmd
+                .arg("list")
+                .args(["--output", format, "--sort", "name", arg, "1"])
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap()
+        });
+        let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+        let output = cmd
+            .arg("list")
+            .args(["--output", format, "--sort", "name"])
+            .output()
+            .unwrap();
+        outputs[0] = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("list")
+        .args(["--page-size", "1", "--page-token", "bogus"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid page token"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("list")
+        .args(["--page-size", "1", "--limit", "1"])
+        .assert()
+        .failure();
+}
+
+#[rstest]
+fn test_download_files_glob_success(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server(ip_address, false);
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+    ctx.create_test_file(AppType::Server, "xyz", "grpc");
+    ctx.create_test_file(AppType::Server, "a.log", "0123456789");
+    ctx.create_test_file(AppType::Server, "b.log", "log");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download")
+        .args(["--file", "*.log", "--file", "abc", "--file", "a.log"])
+        .args(["--jobs", "2"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("a.log      ok"))
+        .stdout(predicate::str::contains("b.log      ok"))
+        .stdout(predicate::str::contains("abc        ok"));
+
+    for name in ["a.log", "b.log", "abc"] {
+        assert!(ctx.client.dir.path().join(name).exists());
+    }
+    assert!(!ctx.client.dir.path().join("xyz").exists());
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download")
+        .args(["--file", "xyz", "--file", "missing"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("xyz        ok"))
+        .stdout(predicate::str::contains("missing    failed"))
+        .stderr(predicate::str::contains("1 of 2 downloads failed"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download")
+        .args(["--file", "*.txt"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("No files match *.txt"));
+}
+
+#[rstest]
+fn test_transfer_progress_success(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server(ip_address, false);
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+    ctx.create_test_file(AppType::Client, "xyz", "grpc");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download")
+        .args(["--file", "abc"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("abc: 5 B of 5 B (100%)"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "xyz"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("xyz: 4 B of 4 B (100%)"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("--quiet")
+        .arg("download")
+        .args(["--file", "abc"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("(100%)").not());
+}
+
+#[rstest]
+fn test_retry_success(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.args(["--retries", "0"]).arg("list").assert().failure();
+
+    // Client started before the server keeps reconnecting until it is up
+    let client = std::process::Command::new(cargo_bin("client"))
+        .args(["--port", &ctx.port.to_string()])
+        .args(["--address", &ip_address.to_string(), "--insecure"])
+        .args(["--retries", "50", "--retry-backoff", "100ms"])
+        .args(["--retry-max-backoff", "200ms"])
+        .arg("download")
+        .args(["--file", "abc"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    std::thread::sleep(Duration::from_millis(500));
+    ctx.start_server(ip_address, false);
+
+    let output = client.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("retrying in"));
+    assert!(compare_files(
+        &ctx.client.dir.path().join("abc"),
+        &ctx.server.files[0].abs_path
+    ));
+}
+
+#[rstest]
+fn test_timeouts_failure(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "127.0.0.1".parse().unwrap();
+
+    {
+        // Completes TCP handshakes but never answers
+        let _listener = std::net::TcpListener::bind((ip_address, ctx.port)).unwrap();
+
+        let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+        cmd.args(["--retries", "0", "--request-timeout", "500ms"])
+            .arg("list")
+            .timeout(Duration::from_secs(5))
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("Timeout expired"));
+
+        let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+        cmd.args(["--retries", "0", "--idle-timeout", "500ms"])
+            .arg("list")
+            .timeout(Duration::from_secs(5))
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("No data received for 500ms"));
+    }
+
+    ctx.start_server_with_args(
+        ip_address,
+        false,
+        &[
+            "--request-timeout",
+            "10s",
+            "--idle-timeout",
+            "5s",
+            "--keepalive-interval",
+            "1s",
+        ],
+    );
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.args(["--keepalive-interval", "1s", "--keepalive-timeout", "5s"])
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("abc        5B"));
+}
+
+#[rstest]
+fn test_slow_upload_success(mut ctx: E2ETestContext) {
+    use std::io::Write;
+
+    let ip_address: IpAddr = "127.0.0.1".parse().unwrap();
+    ctx.start_server_with_args(ip_address, false, &["--request-timeout", "500ms"]);
+
+    // Uploads take longer than the request timeout, which only limits how
+    // long responses of other calls take to start
+    let mut client = std::process::Command::new(cargo_bin("client"))
+        .args(["--port", &ctx.port.to_string()])
+        .args(["--address", &ip_address.to_string(), "--insecure"])
+        .args(["--request-timeout", "500ms", "--idle-timeout", "5s"])
+        .arg("upload")
+        .args(["--file", "slow", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut stdin = client.stdin.take().unwrap();
+    for part in ["slow ", "but ", "steady"] {
+        stdin.write_all(part.as_bytes()).unwrap();
+        stdin.flush().unwrap();
+        std::thread::sleep(Duration::from_millis(600));
+    }
+    drop(stdin);
+
+    let output = client.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read(ctx.server.dir.path().join("slow")).unwrap(),
+        b"slow but steady"
+    );
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.args(["--request-timeout", "500ms"])
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("slow"));
+}
+
+#[rstest]
+fn test_stdio_transfer_success(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server(ip_address, false);
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+    ctx.create_test_file(AppType::Server, "xyz", "grpc");
+
+    // Progress and logs don't get mixed into downloaded data
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download")
+        .args(["--file", "abc", "-"])
+        .assert()
+        .success()
+        .stdout("hello")
+        .stderr(predicate::str::contains("abc: 5 B of 5 B (100%)"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "piped", "-"])
+        .write_stdin("streamed")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("piped: 8 B,"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download")
+        .args(["--file", "piped", "-"])
+        .assert()
+        .success()
+        .stdout("streamed");
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download")
+        .args(["--file", "*", "-"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Only a single file can be written to \"-\", 3 match",
+        ));
+}
+
+#[rstest]
+fn test_sparse_transfer_success(mut ctx: E2ETestContext) {
+    use std::io::{Seek, SeekFrom, Write};
+    use std::os::unix::fs::MetadataExt;
+
+    const SIZE: u64 = 64 * 1024 * 1024;
+
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server(ip_address, false);
+
+    // Data in the middle of holes, the file ends with one
+    let disk = ctx.client.dir.path().join("disk.img");
+    let mut file = fs::File::create(&disk).unwrap();
+    file.set_len(SIZE).unwrap();
+    file.seek(SeekFrom::Start(SIZE / 2)).unwrap();
+    file.write_all(b"hello").unwrap();
+    file.sync_all().unwrap();
+    drop(file);
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "disk.img"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    let downloaded = ctx.client.dir.path().join("downloaded.img");
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download")
+        .args(["--file", "disk.img"])
+        .arg(&downloaded)
+        .assert()
+        .success();
+
+    for path in [ctx.server.dir.path().join("disk.img"), downloaded] {
+        assert!(compare_files(&disk, &path));
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.len(), SIZE);
+        // Holes aren't written as zeros, 512 byte blocks
+        assert!(metadata.blocks() * 512 < SIZE / 8);
+    }
+
+    // Holes are written as zeros where the output can't seek
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    let output = cmd
+        .arg("download")
+        .args(["--file", "disk.img", "-"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, fs::read(&disk).unwrap());
+
+    // Apparent size beyond free disk space, only data takes space
+    let huge = ctx.client.dir.path().join("huge.img");
+    let file = fs::File::create(&huge).unwrap();
+    file.set_len(1 << 40).unwrap();
+    drop(file);
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "huge.img"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+    let metadata = fs::metadata(ctx.server.dir.path().join("huge.img")).unwrap();
+    assert_eq!(metadata.len(), 1 << 40);
+}
+
+#[rstest]
+fn test_delta_transfer_success(mut ctx: E2ETestContext) {
+    use rand::{thread_rng, RngCore};
+
+    const SIZE: usize = 8 * 1024 * 1024;
+
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server(ip_address, false);
+
+    let mut content = vec![0; SIZE];
+    thread_rng().fill_bytes(&mut content);
+    let local = ctx.client.dir.path().join("disk.img");
+    let remote = ctx.server.dir.path().join("disk.img");
+    fs::write(&local, &content).unwrap();
+    fs::write(&remote, &content).unwrap();
+
+    // Changed in place, inserted and truncated
+    content[1000..1010].copy_from_slice(b"0123456789");
+    content.splice(SIZE / 2..SIZE / 2, b"inserted".iter().copied());
+    content.truncate(SIZE - 4096);
+    fs::write(&local, &content).unwrap();
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    let assert = cmd
+        .arg("upload")
+        .args(["--file", "disk.img", "--delta"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("disk.img: sent"));
+    assert_eq!(fs::read(&remote).unwrap(), content);
+    assert!(sent_bytes(&assert.get_output().stderr) < SIZE as u64 / 100);
+
+    // The other way round
+    content[SIZE / 4] ^= 0xff;
+    fs::write(&remote, &content).unwrap();
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    let assert = cmd
+        .arg("download")
+        .args(["--file", "disk.img", "--delta"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+    assert_eq!(fs::read(&local).unwrap(), content);
+    assert!(sent_bytes(&assert.get_output().stderr) < SIZE as u64 / 100);
+
+    // Missing copies are transferred whole
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "copy.img", "--delta"])
+        .arg(&local)
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read(ctx.server.dir.path().join("copy.img")).unwrap(),
+        content
+    );
+
+    let leftovers = fs::read_dir(ctx.server.dir.path())
+        .unwrap()
+        .chain(fs::read_dir(ctx.client.dir.path()).unwrap())
+        .filter(|entry| {
+            let name = entry.as_ref().unwrap().file_name();
+            name.to_string_lossy().ends_with(".ft-part")
+        })
+        .count();
+    assert_eq!(leftovers, 0);
+}
+
+/// Number of new bytes from "NAME: sent N of M bytes" log line.
+fn sent_bytes(stderr: &[u8]) -> u64 {
+    let stderr = String::from_utf8_lossy(stderr);
+    let (_, sent) = stderr.split_once(": sent ").unwrap();
+    sent.split_whitespace().next().unwrap().parse().unwrap()
+}
+
+#[rstest]
+#[case::tar("tar")]
+#[case::tar_zst("tar-zst")]
+#[case::zip("zip")]
+fn test_archive_success(mut ctx: E2ETestContext, #[case] format: &str) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+
+    let share_dir = ctx.server.dir.path().join("docs");
+    fs::create_dir_all(share_dir.join("reports/2022")).unwrap();
+    fs::write(share_dir.join("readme"), "hello").unwrap();
+    fs::write(share_dir.join("reports/2022/q4"), "grpc").unwrap();
+
+    let share_arg = format!("docs={}", share_dir.to_str().unwrap());
+    ctx.start_server_with_args(ip_address, false, &["--share", &share_arg]);
+
+    let archive = ctx.client.dir.path().join("docs.archive");
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download-archive")
+        .args(["--remote", "docs:", "--format", format])
+        .arg(&archive)
+        .assert()
+        .success();
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload-archive")
+        .args(["--remote", "copy", "--format", format])
+        .arg(&archive)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Extracted 2 files"));
+
+    let copy_dir = ctx.server.dir.path().join("copy");
+    assert!(compare_files(
+        &copy_dir.join("readme"),
+        &share_dir.join("readme")
+    ));
+    assert!(compare_files(
+        &copy_dir.join("reports/2022/q4"),
+        &share_dir.join("reports/2022/q4")
+    ));
+
+    // Subdirectory streamed through standard output
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    let output = cmd
+        .arg("download-archive")
+        .args(["--remote", "docs:reports", "--format", format, "-"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload-archive")
+        .args(["--remote", "docs:restored", "--format", format, "-"])
+        .write_stdin(output.stdout)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Extracted 1 files"));
+    assert!(compare_files(
+        &share_dir.join("restored/2022/q4"),
+        &share_dir.join("reports/2022/q4")
+    ));
+}
+
+/// Tar archive of a single file, built by hand to contain names which
+/// archivers refuse to write.
+fn tar_with_entry(name: &str, content: &[u8]) -> Vec<u8> {
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", content.len()).as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0");
+    header[156] = b'0';
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|byte| *byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+
+    let mut archive = header.to_vec();
+    archive.extend_from_slice(content);
+    // Padding of the last block and two empty blocks ending the archive
+    archive.resize(archive.len().next_multiple_of(512) + 1024, 0);
+    archive
+}
+
+#[rstest]
+fn test_archive_failure(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server(ip_address, false);
+
+    for name in ["../evil", "/tmp/evil", "a/../../evil"] {
+        let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+        cmd.arg("upload-archive")
+            .arg("-")
+            .write_stdin(tar_with_entry(name, b"x"))
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("Invalid archive entry"));
+    }
+    assert!(!ctx
+        .server
+        .dir
+        .path()
+        .parent()
+        .unwrap()
+        .join("evil")
+        .exists());
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload-archive")
+        .arg("-")
+        .write_stdin(tar_with_entry("ok", b"x"))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Extracted 1 files"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download-archive")
+        .args(["--remote", "missing", "-"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("NotFound"));
+}
+
+#[rstest]
+fn test_preserve_success(mut ctx: E2ETestContext) {
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server(ip_address, false);
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+    ctx.create_test_file(AppType::Client, "xyz", "grpc");
+
+    let modified = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    for (path, mode) in [
+        (&ctx.server.files[0].abs_path, 0o700),
+        (&ctx.client.files[0].abs_path, 0o751),
+    ] {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "xyz", "--preserve"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download")
+        .args(["--file", "abc", "--preserve"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    for (path, mode) in [
+        (ctx.server.dir.path().join("xyz"), 0o751),
+        (ctx.client.dir.path().join("abc"), 0o700),
+    ] {
+        let metadata = fs::metadata(path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, mode);
+        assert_eq!(metadata.modified().unwrap(), modified);
+    }
+
+    // Without --preserve files get the time of the transfer
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "xyz"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    let metadata = fs::metadata(ctx.server.dir.path().join("xyz")).unwrap();
+    assert!(metadata.modified().unwrap() > SystemTime::now() - Duration::from_secs(60));
+
+    // Delta uploads replace the file with its attributes already applied
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "xyz", "--delta", "--preserve"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    let metadata = fs::metadata(ctx.server.dir.path().join("xyz")).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o751);
+    assert_eq!(metadata.modified().unwrap(), modified);
+}
+
+#[rstest]
+fn test_replace_keeps_metadata_success(mut ctx: E2ETestContext) {
+    use std::os::unix::fs::PermissionsExt;
+
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server(ip_address, false);
+    ctx.create_test_file(AppType::Server, "xyz", "hello");
+    ctx.create_test_file(AppType::Client, "xyz", "grpc");
+
+    let remote = ctx.server.dir.path().join("xyz");
+    fs::set_permissions(&remote, fs::Permissions::from_mode(0o640)).unwrap();
+    xattr::set(&remote, "user.origin", b"test").unwrap();
+
+    // Without --preserve and --xattrs the replaced file's metadata is kept
+    for delta in [false, true] {
+        let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+        cmd.arg("upload")
+            .args(["--file", "xyz"])
+            .args(["--directory", ctx.client.dir.path().to_str().unwrap()]);
+        if delta {
+            cmd.arg("--delta");
+        }
+        cmd.assert().success();
+
+        assert_eq!(fs::read_to_string(&remote).unwrap(), "grpc");
+        let metadata = fs::metadata(&remote).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+        assert_eq!(
+            xattr::get(&remote, "user.origin").unwrap(),
+            Some(b"test".to_vec())
+        );
+    }
+}
+
+#[rstest]
+fn test_xattrs_success(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server_with_args(ip_address, false, &["--xattrs"]);
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+    ctx.create_test_file(AppType::Client, "xyz", "grpc");
+
+    for path in [&ctx.server.files[0].abs_path, &ctx.client.files[0].abs_path] {
+        xattr::set(path, "user.checksum", b"1234").unwrap();
+        xattr::set(path, "user.origin", b"test").unwrap();
+    }
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "xyz", "--xattrs"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download")
+        .args(["--file", "abc", "--xattrs"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .success();
+
+    for path in [
+        ctx.server.dir.path().join("xyz"),
+        ctx.client.dir.path().join("abc"),
+    ] {
+        assert_eq!(
+            xattr::get(&path, "user.checksum").unwrap(),
+            Some(b"1234".to_vec())
+        );
+        assert_eq!(
+            xattr::get(&path, "user.origin").unwrap(),
+            Some(b"test".to_vec())
+        );
+    }
+
+    // Without --xattrs only the content is transferred
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download")
+        .args(["--file", "abc"])
+        .arg(ctx.client.dir.path().join("plain"))
+        .assert()
+        .success();
+
+    let plain = ctx.client.dir.path().join("plain");
+    assert_eq!(xattr::get(plain, "user.checksum").unwrap(), None);
+}
+
+#[rstest]
+fn test_xattrs_failure(mut ctx: E2ETestContext) {
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server(ip_address, false);
+    ctx.create_test_file(AppType::Server, "abc", "hello");
+    ctx.create_test_file(AppType::Client, "xyz", "grpc");
+    xattr::set(&ctx.client.files[0].abs_path, "user.checksum", b"1234").unwrap();
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("download")
+        .args(["--file", "abc", "--xattrs"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Extended attributes are disabled"));
+
+    let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+    cmd.arg("upload")
+        .args(["--file", "xyz", "--xattrs"])
+        .args(["--directory", ctx.client.dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Extended attributes are disabled"));
+    assert!(!ctx.server.dir.path().join("xyz").exists());
+}
+
+#[rstest]
+fn test_sync_success(mut ctx: E2ETestContext) {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let ip_address: IpAddr = "0.0.0.0".parse().unwrap();
+    ctx.start_server(ip_address, false);
+
+    let local = ctx.client.dir.path().join("project");
+    let remote = ctx.server.dir.path().join("mirror");
+    fs::create_dir_all(local.join("src/bin")).unwrap();
+    fs::write(local.join("readme"), "hello").unwrap();
+    fs::write(local.join("src/lib.rs"), "grpc").unwrap();
+    fs::write(local.join("src/bin/main.rs"), "tonic").unwrap();
+
+    let sync = |args: &[&str]| {
+        let mut cmd = get_base_client_cmd(&ctx, &ip_address, false);
+        cmd.arg("sync")
+            .args(["--remote", "mirror"])
+            .args(["--directory", local.to_str().unwrap()])
+            .args(args)
+            .assert()
+    };
+    let set_modified = |path: &std::path::Path, secs: u64| {
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    };
+
+    // Dry run changes nothing, missing remote directory is empty
+    sync(&["--dry-run"])
+        .success()
+        .stdout(predicate::str::contains("src/bin/main.rs"))
+        .stdout(predicate::str::contains("dry run"));
+    assert!(!remote.exists());
+
+    sync(&[]).success().stdout(predicate::str::contains("ok"));
+    assert_eq!(fs::read(remote.join("src/bin/main.rs")).unwrap(), b"tonic");
+    sync(&[])
+        .success()
+        .stdout(predicate::str::contains("Already in sync"));
+
+    // Pull with --delete mirrors the server
+    fs::write(remote.join("src/lib.rs"), "prost").unwrap();
+    set_modified(&remote.join("src/lib.rs"), 1_500_000_000);
+    fs::remove_file(remote.join("readme")).unwrap();
+    sync(&["--direction", "pull", "--delete"])
+        .success()
+        .stdout(predicate::str::contains("delete local"));
+    assert_eq!(fs::read(local.join("src/lib.rs")).unwrap(), b"prost");
+    assert!(!local.join("readme").exists());
+
+    // Two-way keeps the newer version and copies new files both ways
+    fs::write(local.join("notes"), "local").unwrap();
+    fs::write(remote.join("todo"), "remote").unwrap();
+    fs::write(local.join("src/lib.rs"), "tokio").unwrap();
+    fs::write(remote.join("src/bin/main.rs"), "clap").unwrap();
+    set_modified(&local.join("src/bin/main.rs"), 1_000_000_000);
+    sync(&["--direction", "two-way"]).success();
+    assert_eq!(fs::read(remote.join("notes")).unwrap(), b"local");
+    assert_eq!(fs::read(local.join("todo")).unwrap(), b"remote");
+    assert_eq!(fs::read(remote.join("src/lib.rs")).unwrap(), b"tokio");
+    assert_eq!(fs::read(local.join("src/bin/main.rs")).unwrap(), b"clap");
+
+    // Same size and time, only checksums tell the difference
+    let modified = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    fs::write(remote.join("notes"), "LOCAL").unwrap();
+    set_modified(&local.join("notes"), modified);
+    set_modified(&remote.join("notes"), modified);
+    sync(&["--direction", "two-way", "--conflict", "skip"])
+        .success()
+        .stdout(predicate::str::contains("Already in sync"));
+    sync(&["--direction", "two-way", "--conflict", "skip", "--checksum"])
+        .success()
+        .stdout(predicate::str::contains("skip conflict"));
+    sync(&["--checksum", "--delta"]).success();
+    assert_eq!(fs::read(remote.join("notes")).unwrap(), b"local");
+
+    sync(&["--direction", "two-way", "--delete"])
+        .failure()
+        .stderr(predicate::str::contains("--delete only works"));
+}
@@ -0,0 +1,432 @@
+use crate::share::Share;
+use crate::upload_limits::{self, ShareUsage, UploadGuard, UploadLimits};
+use anyhow::Result;
+use async_compression::tokio::{bufread::ZstdDecoder, write::ZstdEncoder};
+use async_zip::base::read::stream::ZipFileReader;
+use async_zip::base::write::ZipFileWriter;
+use async_zip::{Compression, ZipDateTime, ZipEntryBuilder};
+use chrono::{DateTime, Utc};
+use common::temp_file::{self, TempFile};
+use proto::api::ArchiveFormat;
+use std::fs::Metadata;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio_stream::StreamExt;
+use tokio_tar::{Archive, Builder, EntryType};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt};
+use tonic::Status;
+
+const CHUNK_SIZE_BYTES: u64 = 1024 * 1024; // 1 MB
+
+/// Regular file or directory found under the archived directory.
+pub(crate) struct WalkEntry {
+    pub path: PathBuf,
+    /// Path relative to the archived directory, used as entry name
+    pub name: PathBuf,
+    pub metadata: Metadata,
+}
+
+/// Lists directory tree in name order. Symbolic links and special files are
+/// skipped, so that archives can't reach outside of the share, and so are
+/// temporary files of transfers in progress.
+pub(crate) async fn walk(directory: &Path) -> Result<Vec<WalkEntry>> {
+    let mut entries = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(relative) = pending.pop() {
+        let mut dir_stream = fs::read_dir(directory.join(&relative)).await?;
+        let mut children = Vec::new();
+
+        while let Some(dir_entry) = dir_stream.next_entry().await? {
+            let metadata = fs::symlink_metadata(dir_entry.path()).await?;
+            if temp_file::is_temp(&dir_entry.file_name()) {
+                continue;
+            }
+            if metadata.is_file() || metadata.is_dir() {
+                children.push(WalkEntry {
+                    path: dir_entry.path(),
+                    name: relative.join(dir_entry.file_name()),
+                    metadata,
+                });
+            }
+        }
+
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+        // Subdirectories are listed once the current one is done, in order
+        for child in children.iter().rev() {
+            if child.metadata.is_dir() {
+                pending.push(child.name.clone());
+            }
+        }
+        entries.extend(children);
+    }
+
+    Ok(entries)
+}
+
+/// Writes archive of `directory` of the share to `writer` while walking
+/// it, so that nothing is staged on disk.
+pub async fn write_archive<W>(
+    share: &Share,
+    directory: &Path,
+    format: ArchiveFormat,
+    writer: W,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    check_directory(share, directory).await?;
+    let entries = walk(directory).await?;
+
+    match format {
+        ArchiveFormat::Tar => {
+            let writer = write_tar(entries, writer).await?;
+            writer.into_inner().await?.shutdown().await?;
+        }
+        ArchiveFormat::TarZstd => {
+            let writer = write_tar(entries, ZstdEncoder::new(writer)).await?;
+            writer.into_inner().await?.shutdown().await?;
+        }
+        ArchiveFormat::Zip => write_zip(entries, writer).await?,
+    }
+
+    Ok(())
+}
+
+async fn write_tar<W>(entries: Vec<WalkEntry>, writer: W) -> Result<Builder<W>>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let mut builder = Builder::new(writer);
+    builder.follow_symlinks(false);
+
+    for entry in entries {
+        if entry.metadata.is_dir() {
+            builder.append_dir(&entry.name, &entry.path).await?;
+        } else {
+            builder
+                .append_path_with_name(&entry.path, &entry.name)
+                .await?;
+        }
+    }
+
+    Ok(builder)
+}
+
+async fn write_zip<W>(entries: Vec<WalkEntry>, writer: W) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut zip = ZipFileWriter::with_tokio(writer);
+
+    for entry in entries {
+        let mut name = entry.name.to_string_lossy().into_owned();
+        let compression = if entry.metadata.is_dir() {
+            name.push('/');
+            Compression::Stored
+        } else {
+            Compression::Deflate
+        };
+
+        let mut builder = ZipEntryBuilder::new(name.into(), compression)
+            .unix_permissions(entry.metadata.permissions().mode() as u16);
+        if let Ok(modified) = entry.metadata.modified() {
+            builder = builder
+                .last_modification_date(ZipDateTime::from_chrono(&DateTime::<Utc>::from(modified)));
+        }
+
+        if entry.metadata.is_dir() {
+            zip.write_entry_whole(builder, &[]).await?;
+        } else {
+            let mut file = fs::File::open(&entry.path).await?;
+            let mut entry_writer = zip.write_entry_stream(builder).await?.compat_write();
+            tokio::io::copy(&mut file, &mut entry_writer).await?;
+            entry_writer.into_inner().close().await?;
+        }
+    }
+
+    zip.close().await?.into_inner().shutdown().await?;
+
+    Ok(())
+}
+
+/// Result of extracting an archive.
+#[derive(Debug, Default)]
+pub struct Extracted {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Extracts archive read from `reader` into `directory` of the share.
+/// Entries may only create regular files and directories inside of it,
+/// and every file is checked against upload limits.
+pub async fn extract_archive<R>(
+    reader: R,
+    format: ArchiveFormat,
+    share: &Share,
+    directory: &Path,
+    limits: UploadLimits,
+) -> Result<Extracted>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    check_directory(share, directory).await?;
+    fs::create_dir_all(directory).await?;
+
+    let mut usage = ShareUsage::new(share).await?;
+
+    let reader = BufReader::new(reader);
+    match format {
+        ArchiveFormat::Tar => extract_tar(reader, share, directory, limits, &mut usage).await,
+        ArchiveFormat::TarZstd => {
+            extract_tar(
+                ZstdDecoder::new(reader),
+                share,
+                directory,
+                limits,
+                &mut usage,
+            )
+            .await
+        }
+        ArchiveFormat::Zip => extract_zip(reader, share, directory, limits, &mut usage).await,
+    }
+}
+
+async fn extract_tar<R>(
+    reader: R,
+    share: &Share,
+    directory: &Path,
+    limits: UploadLimits,
+    usage: &mut ShareUsage,
+) -> Result<Extracted>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut archive = Archive::new(reader);
+    let mut entries = archive.entries()?;
+    let mut extracted = Extracted::default();
+
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+
+        match entry.header().entry_type() {
+            EntryType::Directory => {
+                if let Some(path) = entry_target(directory, &entry_path).await? {
+                    fs::create_dir_all(path).await?;
+                }
+            }
+            EntryType::Regular | EntryType::Continuous => {
+                let path = entry_target(directory, &entry_path)
+                    .await?
+                    .ok_or_else(|| invalid_entry(&entry_path))?;
+                let size = entry.header().size()?;
+                extracted.bytes +=
+                    extract_file(&mut entry, &path, Some(size), share, limits, usage).await?;
+                extracted.files += 1;
+            }
+            // Metadata of following entries
+            EntryType::XGlobalHeader | EntryType::XHeader => {}
+            entry_type => Err(Status::invalid_argument(format!(
+                "Unsupported archive entry {entry_path:?} of type {entry_type:?}"
+            )))?,
+        }
+    }
+
+    Ok(extracted)
+}
+
+async fn extract_zip<R>(
+    reader: R,
+    share: &Share,
+    directory: &Path,
+    limits: UploadLimits,
+    usage: &mut ShareUsage,
+) -> Result<Extracted>
+where
+    R: tokio::io::AsyncBufRead + Unpin + Send,
+{
+    let mut zip = ZipFileReader::with_tokio(reader);
+    let mut extracted = Extracted::default();
+
+    while let Some(mut entry) = zip.next_with_entry().await? {
+        let zip_entry = entry.reader().entry();
+        let entry_path = PathBuf::from(zip_entry.filename().as_str()?);
+        let is_dir = zip_entry.dir()?;
+
+        if is_dir {
+            if let Some(path) = entry_target(directory, &entry_path).await? {
+                fs::create_dir_all(path).await?;
+            }
+        } else {
+            let path = entry_target(directory, &entry_path)
+                .await?
+                .ok_or_else(|| invalid_entry(&entry_path))?;
+            let mut reader = entry.reader_mut().compat();
+            extracted.bytes += extract_file(&mut reader, &path, None, share, limits, usage).await?;
+            extracted.files += 1;
+        }
+
+        zip = entry.done().await?;
+    }
+
+    Ok(extracted)
+}
+
+/// Resolves entry path inside of `directory`, `None` for the directory
+/// itself. Absolute paths, `..` and paths through symbolic links already in
+/// the share are rejected.
+async fn entry_target(directory: &Path, entry_path: &Path) -> Result<Option<PathBuf>> {
+    let mut relative = PathBuf::new();
+
+    for component in entry_path.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            _ => Err(invalid_entry(entry_path))?,
+        }
+    }
+
+    if through_symlink(directory, &relative).await? {
+        Err(invalid_entry(entry_path))?;
+    }
+
+    if relative.as_os_str().is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(directory.join(relative)))
+    }
+}
+
+/// Whether any existing part of `relative` path under `directory` is a
+/// symbolic link, which could lead out of it.
+async fn through_symlink(directory: &Path, relative: &Path) -> io::Result<bool> {
+    let mut path = directory.to_path_buf();
+
+    for component in relative.components() {
+        path.push(component);
+
+        match fs::symlink_metadata(&path).await {
+            Ok(metadata) if metadata.file_type().is_symlink() => return Ok(true),
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(false)
+}
+
+/// Checks that archived, extracted or listed directory of the share isn't
+/// reached through a symbolic link.
+pub(crate) async fn check_directory(share: &Share, directory: &Path) -> Result<()> {
+    let relative = directory.strip_prefix(&share.directory)?;
+
+    if through_symlink(&share.directory, relative).await? {
+        Err(Status::invalid_argument(format!(
+            "Invalid directory: {relative:?}"
+        )))?;
+    }
+
+    Ok(())
+}
+
+fn invalid_entry(entry_path: &Path) -> Status {
+    Status::invalid_argument(format!("Invalid archive entry: {entry_path:?}"))
+}
+
+/// Writes single archive entry, replacing an existing file only if it
+/// succeeds. Returns number of written bytes.
+async fn extract_file<R>(
+    entry: &mut R,
+    path: &Path,
+    size: Option<u64>,
+    share: &Share,
+    limits: UploadLimits,
+    usage: &mut ShareUsage,
+) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+{
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let replaced = upload_limits::replaced_size(path).await;
+    let guard = UploadGuard::with_usage(limits, share, usage, replaced, size).await?;
+    let (temp_file, mut file) = TempFile::create(path).await?;
+
+    let mut written = 0;
+    let mut handle = entry.take(CHUNK_SIZE_BYTES);
+
+    loop {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE_BYTES as usize);
+        let n = handle.read_to_end(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        handle.set_limit(CHUNK_SIZE_BYTES);
+
+        guard.check(written, n as u64, &share.name).await?;
+        file.write_all(&chunk).await?;
+        written += n as u64;
+    }
+
+    file.sync_all().await?;
+    temp_file.persist().await?;
+    usage.update(replaced, written);
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn entry_target_stays_in_directory() {
+        let dir = TempDir::new("archive").unwrap();
+        let directory = dir.path();
+
+        assert_eq!(
+            entry_target(directory, Path::new("./")).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            entry_target(directory, Path::new("./a/b")).await.unwrap(),
+            Some(directory.join("a/b"))
+        );
+
+        for entry_path in ["../a", "a/../../b", "a/..", "/etc/passwd"] {
+            let err = entry_target(directory, Path::new(entry_path))
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("Invalid archive entry"), "{err:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn entry_target_rejects_symlinked_parents() {
+        let dir = TempDir::new("archive").unwrap();
+        let outside = TempDir::new("outside").unwrap();
+        let directory = dir.path();
+        std::fs::create_dir(directory.join("real")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), directory.join("link")).unwrap();
+
+        assert!(entry_target(directory, Path::new("link/a")).await.is_err());
+        assert!(entry_target(directory, Path::new("link")).await.is_err());
+        assert!(entry_target(directory, Path::new("real/a")).await.is_ok());
+
+        // Missing parts can't be links yet
+        assert!(!through_symlink(directory, Path::new("real/new/a"))
+            .await
+            .unwrap());
+        assert!(through_symlink(directory, Path::new("link/new/a"))
+            .await
+            .unwrap());
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::Result;
+use common::rotating_file::RotatingFile;
+use serde::Serialize;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use tonic::{Request, Status};
+use tracing::error;
+use x509_parser::parse_x509_certificate;
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    List,
+    Download,
+    Upload,
+    DownloadArchive,
+    UploadArchive,
+    Signatures,
+    DownloadDelta,
+    UploadDelta,
+    ListTree,
+    Delete,
+}
+
+/// Identity of the client which issued a request.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Peer {
+    pub address: Option<SocketAddr>,
+    pub identity: Option<String>,
+}
+
+impl Peer {
+    pub fn from_request<T>(request: &Request<T>) -> Self {
+        let identity = request.peer_certs().and_then(|certs| {
+            let cert = certs.first()?;
+            let (_, cert) = parse_x509_certificate(cert.get_ref()).ok()?;
+            Some(cert.subject().to_string())
+        });
+
+        Self {
+            address: request.remote_addr(),
+            identity,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    timestamp: String,
+    peer: &'a Peer,
+    operation: Operation,
+    path: &'a str,
+    bytes: u64,
+    result: &'static str,
+    error: Option<String>,
+    duration_ms: u128,
+}
+
+/// Append-only JSON lines log of file operations, kept apart from tracing.
+pub struct AuditLog {
+    file: Arc<Mutex<RotatingFile>>,
+}
+
+impl AuditLog {
+    pub fn new(path: &Path, max_size: u64, max_files: usize) -> Result<Self> {
+        let file = RotatingFile::new(path, max_size, max_files)?;
+
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    async fn write(&self, line: Vec<u8>) -> Result<()> {
+        let file = Arc::clone(&self.file);
+
+        tokio::task::spawn_blocking(move || {
+            let mut file = file.lock().unwrap();
+            file.write_all(&line)?;
+            file.sync()
+        })
+        .await??;
+
+        Ok(())
+    }
+}
+
+/// Single audited operation, written to the audit log when finished.
+pub struct AuditEvent {
+    log: Option<Arc<AuditLog>>,
+    peer: Peer,
+    operation: Operation,
+    pub path: String,
+    pub bytes: u64,
+    started: Instant,
+}
+
+impl AuditEvent {
+    pub fn new(log: Option<Arc<AuditLog>>, peer: Peer, operation: Operation, path: String) -> Self {
+        Self {
+            log,
+            peer,
+            operation,
+            path,
+            bytes: 0,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn peer(&self) -> &Peer {
+        &self.peer
+    }
+
+    /// Records operation rejected before it started and passes the status on.
+    pub async fn reject(self, status: Status) -> Status {
+        self.finish(&Err(status.clone().into())).await;
+        status
+    }
+
+    pub async fn finish(self, result: &Result<()>) {
+        let Some(log) = self.log else {
+            return;
+        };
+
+        let entry = AuditEntry {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            peer: &self.peer,
+            operation: self.operation,
+            path: &self.path,
+            bytes: self.bytes,
+            result: if result.is_ok() { "ok" } else { "error" },
+            error: result.as_ref().err().map(|err| err.to_string()),
+            duration_ms: self.started.elapsed().as_millis(),
+        };
+
+        let mut line = match serde_json::to_vec(&entry) {
+            Ok(line) => line,
+            Err(err) => {
+                error!(%err);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        if let Err(err) = log.write(line).await {
+            error!(%err, "Failed to write audit log entry");
+        }
+    }
+}
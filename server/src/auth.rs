@@ -0,0 +1,57 @@
+use std::sync::Arc;
+use tonic::{service::Interceptor, Request, Status};
+use tracing::warn;
+
+/// Interceptor admitting only calls with `authorization: Bearer TOKEN`
+/// metadata holding one of the configured tokens. Without any token
+/// configured every call is admitted.
+#[derive(Clone, Debug)]
+pub struct TokenCheck {
+    tokens: Arc<Vec<String>>,
+}
+
+impl TokenCheck {
+    pub fn new(tokens: Vec<String>) -> Self {
+        Self {
+            tokens: Arc::new(tokens),
+        }
+    }
+
+    fn is_valid(&self, token: &str) -> bool {
+        // Every token is compared, so that timing doesn't tell which matched
+        self.tokens.iter().fold(false, |valid, expected| {
+            valid | constant_time_eq(expected, token)
+        })
+    }
+}
+
+impl Interceptor for TokenCheck {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        if self.tokens.is_empty() {
+            return Ok(request);
+        }
+
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        match token {
+            Some(token) if self.is_valid(token) => Ok(request),
+            _ => {
+                warn!(peer = ?request.remote_addr(), "Missing or invalid token");
+                Err(Status::unauthenticated("Missing or invalid token"))
+            }
+        }
+    }
+}
+
+/// Compares in time depending only on the lengths.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
@@ -1,36 +1,131 @@
-use clap::{builder::ArgPredicate, Parser};
-use std::{net::IpAddr, path::PathBuf};
+use crate::listen::ListenAddress;
+use crate::share::ShareMode;
+use clap::Parser;
+use common::logging::LogFormat;
+use std::{net::IpAddr, path::PathBuf, time::Duration};
 use tracing::Level;
 
+/// Command line flags take precedence over environment variables,
+/// which take precedence over the configuration file.
 #[derive(Parser)]
 #[command(version)]
 pub struct Cli {
-    #[arg(short, long)]
-    pub directory: PathBuf,
-    #[arg(short = 'H', long, default_value = "127.0.0.1")]
-    pub address: IpAddr,
-    #[arg(short, long)]
-    pub port: Option<u16>,
-    #[arg(short, long, default_value = "info")]
-    pub verbose: Level,
+    /// TOML configuration file
+    #[arg(short, long, env = "FT_SERVER_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Print effective configuration and exit
+    #[arg(long)]
+    pub print_config: bool,
+    /// Directory of the default share
+    #[arg(short, long, env = "FT_SERVER_DIRECTORY")]
+    pub directory: Option<PathBuf>,
+    /// Additional share exported as NAME=DIRECTORY, can be repeated
+    #[arg(short, long = "share", value_name = "NAME=DIRECTORY")]
+    pub shares: Vec<String>,
+    /// Mode of shares which don't set their own [default: read-write]
+    #[arg(short, long, value_enum, env = "FT_SERVER_MODE")]
+    pub mode: Option<ShareMode>,
+    /// Maximum size of uploaded file in bytes
+    #[arg(long, env = "FT_SERVER_MAX_FILE_SIZE")]
+    pub max_file_size: Option<u64>,
+    /// Free disk space in bytes which uploads must leave [default: 67108864]
+    #[arg(long, env = "FT_SERVER_MIN_FREE_SPACE")]
+    pub min_free_space: Option<u64>,
+    /// Transfer extended attributes of files if clients ask for it,
+    /// `--xattrs=false` overrides the configuration file
     #[arg(
         long,
-        required = true,
-        default_value_if("insecure", ArgPredicate::IsPresent, None)
+        env = "FT_SERVER_XATTRS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
     )]
+    pub xattrs: Option<bool>,
+    /// Namespaces of transferred extended attributes [default: user]
+    #[arg(long, env = "FT_SERVER_XATTR_NAMESPACES", value_delimiter = ',')]
+    pub xattr_namespaces: Vec<String>,
+    /// Maximum number of calls handled at once
+    #[arg(long, env = "FT_SERVER_MAX_CONCURRENT_REQUESTS")]
+    pub max_concurrent_requests: Option<usize>,
+    /// Maximum number of calls handled at once for a single client address
+    #[arg(long, env = "FT_SERVER_MAX_CONCURRENT_REQUESTS_PER_PEER")]
+    pub max_concurrent_requests_per_peer: Option<usize>,
+    /// Maximum number of calls started per second
+    #[arg(long, env = "FT_SERVER_MAX_REQUESTS_PER_SECOND")]
+    pub max_requests_per_second: Option<u32>,
+    /// Maximum number of calls started per second by a single client address
+    #[arg(long, env = "FT_SERVER_MAX_REQUESTS_PER_SECOND_PER_PEER")]
+    pub max_requests_per_second_per_peer: Option<u32>,
+    /// Deadline of a call until its response starts, e.g. 30s. Uploads are
+    /// limited by the idle timeout instead
+    #[arg(long, env = "FT_SERVER_REQUEST_TIMEOUT", value_parser = humantime::parse_duration)]
+    pub request_timeout: Option<Duration>,
+    /// Abort streams without any message for this long, 0s disables [default: 1m]
+    #[arg(long, env = "FT_SERVER_IDLE_TIMEOUT", value_parser = humantime::parse_duration)]
+    pub idle_timeout: Option<Duration>,
+    /// Interval of HTTP/2 keepalive pings, disabled by default
+    #[arg(long, env = "FT_SERVER_KEEPALIVE_INTERVAL", value_parser = humantime::parse_duration)]
+    pub keepalive_interval: Option<Duration>,
+    /// Close connection if keepalive ping isn't answered in time [default: 20s]
+    #[arg(long, env = "FT_SERVER_KEEPALIVE_TIMEOUT", value_parser = humantime::parse_duration)]
+    pub keepalive_timeout: Option<Duration>,
+    /// [default: 127.0.0.1]
+    #[arg(short = 'H', long, env = "FT_SERVER_ADDRESS")]
+    pub address: Option<IpAddr>,
+    #[arg(short, long, env = "FT_SERVER_PORT")]
+    pub port: Option<u16>,
+    /// Also accept connections on [http://|https://]IP:PORT or unix:PATH,
+    /// can be repeated. TCP on --address is then only served if --port is set
+    #[arg(short, long, env = "FT_SERVER_LISTEN", value_delimiter = ',')]
+    pub listen: Vec<ListenAddress>,
+    /// Default log level, overridden per module by RUST_LOG [default: info]
+    #[arg(short, long, env = "FT_SERVER_LOG_LEVEL")]
+    pub verbose: Option<Level>,
+    /// [default: full]
+    #[arg(long, value_enum, env = "FT_SERVER_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// Write logs to this file instead of the console
+    #[arg(long, env = "FT_SERVER_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+    /// Size in bytes after which the log file is rotated [default: 10485760]
+    #[arg(long, env = "FT_SERVER_LOG_FILE_MAX_SIZE")]
+    pub log_file_max_size: Option<u64>,
+    /// Number of rotated log files to keep [default: 5]
+    #[arg(long, env = "FT_SERVER_LOG_FILE_MAX_FILES")]
+    pub log_file_max_files: Option<usize>,
+    #[arg(long, env = "FT_SERVER_CERT")]
     pub cert: Option<PathBuf>,
+    #[arg(long, env = "FT_SERVER_KEY")]
+    pub key: Option<PathBuf>,
+    #[arg(long, env = "FT_SERVER_CA_CERT")]
+    pub ca_cert: Option<PathBuf>,
+    /// Listen without TLS, `--insecure=false` overrides the configuration
+    /// file
     #[arg(
+        short,
         long,
-        required = true,
-        default_value_if("insecure", ArgPredicate::IsPresent, None)
+        env = "FT_SERVER_INSECURE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
     )]
-    pub key: Option<PathBuf>,
+    pub insecure: Option<bool>,
+    /// Bearer token which clients must send, can be repeated. Without any,
+    /// tokens aren't checked
     #[arg(
-        long,
-        required = true,
-        default_value_if("insecure", ArgPredicate::IsPresent, None)
+        long = "token",
+        env = "FT_SERVER_TOKENS",
+        value_delimiter = ',',
+        hide_env_values = true
     )]
-    pub ca_cert: Option<PathBuf>,
-    #[arg(short, long, conflicts_with_all = ["key", "cert", "ca_cert"])]
-    pub insecure: bool,
+    pub tokens: Vec<String>,
+    /// Append JSON lines audit trail of file operations to this file
+    #[arg(long, env = "FT_SERVER_AUDIT_LOG")]
+    pub audit_log: Option<PathBuf>,
+    /// Size in bytes after which the audit log is rotated [default: 104857600]
+    #[arg(long, env = "FT_SERVER_AUDIT_LOG_MAX_SIZE")]
+    pub audit_log_max_size: Option<u64>,
+    /// Number of rotated audit log files to keep [default: 10]
+    #[arg(long, env = "FT_SERVER_AUDIT_LOG_MAX_FILES")]
+    pub audit_log_max_files: Option<usize>,
 }
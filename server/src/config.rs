@@ -0,0 +1,509 @@
+use crate::cli::Cli;
+use crate::listen::ListenAddress;
+use crate::rate_limit::RateLimits;
+use crate::share::{Share, ShareMode, Shares};
+use crate::systemd;
+use crate::timeouts::Timeouts;
+use crate::upload_limits::UploadLimits;
+use anyhow::{anyhow, bail, Context, Result};
+use common::attributes::XattrNamespaces;
+use common::logging::{LogConfig, LogFormat};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::unix::io::OwnedFd,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Layer read from the `--config` file, every field is optional.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    directory: Option<PathBuf>,
+    address: Option<IpAddr>,
+    port: Option<u16>,
+    listen: Option<Vec<ListenAddress>>,
+    insecure: Option<bool>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    ca_cert: Option<PathBuf>,
+    tokens: Option<Vec<String>>,
+    mode: Option<ShareMode>,
+    max_file_size: Option<u64>,
+    min_free_space: Option<u64>,
+    xattrs: Option<bool>,
+    xattr_namespaces: Option<Vec<String>>,
+    max_concurrent_requests: Option<usize>,
+    max_concurrent_requests_per_peer: Option<usize>,
+    max_requests_per_second: Option<u32>,
+    max_requests_per_second_per_peer: Option<u32>,
+    #[serde(default, with = "humantime_serde")]
+    request_timeout: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    idle_timeout: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    keepalive_interval: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    keepalive_timeout: Option<Duration>,
+    log: Option<LogConfigFile>,
+    audit_log: Option<AuditLogConfigFile>,
+    #[serde(default)]
+    shares: BTreeMap<String, ShareConfig>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogConfigFile {
+    level: Option<String>,
+    format: Option<LogFormat>,
+    path: Option<PathBuf>,
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuditLogConfigFile {
+    path: Option<PathBuf>,
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+}
+
+/// Effective server configuration, merged from the configuration file,
+/// environment variables and command line flags.
+#[derive(Debug, Serialize)]
+pub struct Config {
+    /// Directory of the default share
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub directory: Option<PathBuf>,
+    pub address: IpAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Additional listeners, replace TCP on `address` and `port` unless
+    /// `port` is set
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub listen: Vec<ListenAddress>,
+    /// Sockets passed by systemd socket activation, replace TCP on `address`
+    /// and `port` unless `port` is set
+    #[serde(skip)]
+    pub activated: Vec<ListenAddress>,
+    pub insecure: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cert: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<PathBuf>,
+    /// Bearer tokens accepted from clients, not checked if empty. Secrets
+    /// aren't printed
+    #[serde(skip)]
+    pub tokens: Vec<String>,
+    /// Mode of the default share and shares which don't set their own
+    pub mode: ShareMode,
+    /// Maximum size of uploaded file in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<u64>,
+    /// Free disk space in bytes which uploads must leave
+    pub min_free_space: u64,
+    /// Whether extended attributes are transferred on request
+    pub xattrs: bool,
+    /// Namespaces of transferred extended attributes, e.g. `user`
+    pub xattr_namespaces: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_requests: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_requests_per_peer: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_requests_per_second: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_requests_per_second_per_peer: Option<u32>,
+    /// Deadline of a call until its response starts
+    #[serde(skip_serializing_if = "Option::is_none", with = "humantime_serde")]
+    pub request_timeout: Option<Duration>,
+    /// Streams without any message for this long are aborted, 0 disables
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Duration,
+    #[serde(skip_serializing_if = "Option::is_none", with = "humantime_serde")]
+    pub keepalive_interval: Option<Duration>,
+    #[serde(skip_serializing_if = "Option::is_none", with = "humantime_serde")]
+    pub keepalive_timeout: Option<Duration>,
+    pub log: LogConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audit_log: Option<AuditLogConfig>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub shares: BTreeMap<String, ShareConfig>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShareConfig {
+    pub directory: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<ShareMode>,
+    /// Maximum size of all files in the share in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
+    /// Certificate subjects allowed to access the share, everyone if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub acl: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditLogConfig {
+    pub path: PathBuf,
+    pub max_size: u64,
+    pub max_files: usize,
+}
+
+impl Config {
+    const DEFAULT_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    const DEFAULT_AUDIT_LOG_MAX_SIZE: u64 = 100 * 1024 * 1024;
+    const DEFAULT_AUDIT_LOG_MAX_FILES: usize = 10;
+    const DEFAULT_MIN_FREE_SPACE: u64 = 64 * 1024 * 1024;
+    const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+    const DEFAULT_XATTR_NAMESPACE: &str = "user";
+
+    /// Loads configuration with sockets `listen_fds` passed by systemd.
+    pub fn load(args: &Cli, listen_fds: &[OwnedFd]) -> Result<Self> {
+        let file = match &args.config {
+            Some(path) => ConfigFile::read(path)?,
+            None => ConfigFile::default(),
+        };
+
+        Self::merge(args, file, listen_fds)
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// Shares exported by the server, including the default one.
+    pub fn shares(&self) -> Shares {
+        let default_share = self.directory.as_ref().map(|directory| Share {
+            name: Shares::DEFAULT_SHARE.to_string(),
+            directory: directory.clone(),
+            mode: self.mode,
+            quota: None,
+            acl: vec![],
+        });
+
+        let shares = self.shares.iter().map(|(name, share)| Share {
+            name: name.clone(),
+            directory: share.directory.clone(),
+            mode: share.mode.unwrap_or(self.mode),
+            quota: share.quota,
+            acl: share.acl.clone(),
+        });
+
+        Shares::new(default_share.into_iter().chain(shares))
+    }
+
+    /// Addresses the server binds, with TLS resolved for every TCP listener.
+    pub fn listeners(&self) -> Vec<ListenAddress> {
+        Self::resolve_listeners(
+            self.address,
+            self.port,
+            &self.listen,
+            &self.activated,
+            self.insecure,
+        )
+    }
+
+    /// Sockets passed by systemd socket activation, with TLS resolved.
+    pub fn activated_listeners(&self) -> Vec<ListenAddress> {
+        self.activated
+            .iter()
+            .map(|listen_address| listen_address.clone().resolve_tls(self.insecure))
+            .collect()
+    }
+
+    fn resolve_listeners(
+        address: IpAddr,
+        port: Option<u16>,
+        listen: &[ListenAddress],
+        activated: &[ListenAddress],
+        insecure: bool,
+    ) -> Vec<ListenAddress> {
+        let default = (port.is_some() || (listen.is_empty() && activated.is_empty())).then(|| {
+            ListenAddress::Tcp {
+                address: SocketAddr::new(address, port.unwrap_or(0)),
+                tls: None,
+            }
+        });
+
+        default
+            .into_iter()
+            .chain(listen.iter().cloned())
+            .map(|listen_address| listen_address.resolve_tls(insecure))
+            .collect()
+    }
+
+    pub fn upload_limits(&self) -> UploadLimits {
+        UploadLimits {
+            max_file_size: self.max_file_size,
+            min_free_space: self.min_free_space,
+        }
+    }
+
+    /// Namespaces of transferred extended attributes, `None` if disabled.
+    pub fn xattr_namespaces(&self) -> Option<XattrNamespaces> {
+        self.xattrs
+            .then(|| XattrNamespaces::new(&self.xattr_namespaces))
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        RateLimits {
+            max_concurrent: self.max_concurrent_requests,
+            max_concurrent_per_peer: self.max_concurrent_requests_per_peer,
+            max_rate: self.max_requests_per_second,
+            max_rate_per_peer: self.max_requests_per_second_per_peer,
+        }
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            request: self.request_timeout,
+            idle: (!self.idle_timeout.is_zero()).then_some(self.idle_timeout),
+            keepalive_interval: self.keepalive_interval,
+            keepalive_timeout: self.keepalive_timeout,
+        }
+    }
+
+    fn merge(args: &Cli, file: ConfigFile, listen_fds: &[OwnedFd]) -> Result<Self> {
+        let directory = args.directory.clone().or(file.directory);
+
+        let mut shares = file.shares;
+        for share in &args.shares {
+            let (name, directory) = share
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid share {share:?}, expected NAME=DIRECTORY"))?;
+            shares.insert(
+                name.to_string(),
+                ShareConfig {
+                    directory: PathBuf::from(directory),
+                    ..shares.get(name).cloned().unwrap_or_default()
+                },
+            );
+        }
+
+        if directory.is_none() && shares.is_empty() {
+            return Err(missing("directory", "--directory", "FT_SERVER_DIRECTORY"))
+                .context("at least one share must be configured");
+        }
+
+        let address = args
+            .address
+            .or(file.address)
+            .unwrap_or(Self::DEFAULT_ADDRESS);
+        let port = args.port.or(file.port);
+        let listen = if args.listen.is_empty() {
+            file.listen.unwrap_or_default()
+        } else {
+            args.listen.clone()
+        };
+
+        let tls_args = args.cert.is_some() || args.key.is_some() || args.ca_cert.is_some();
+        if args.insecure == Some(true) && tls_args {
+            bail!("--insecure conflicts with --cert, --key and --ca-cert");
+        }
+        let insecure = args
+            .insecure
+            .unwrap_or(!tls_args && file.insecure.unwrap_or(false));
+
+        let activated = systemd::listen_addresses(listen_fds)?;
+        let tls = Self::resolve_listeners(address, port, &listen, &activated, insecure)
+            .iter()
+            .chain(&activated)
+            .any(|listen_address| listen_address.clone().resolve_tls(insecure).is_tls());
+
+        let (cert, key, ca_cert) = if !tls {
+            if insecure
+                && args.insecure.is_none()
+                && (file.cert.is_some() || file.key.is_some() || file.ca_cert.is_some())
+            {
+                bail!("configuration file: `insecure` conflicts with `cert`, `key` and `ca_cert`");
+            }
+            (None, None, None)
+        } else {
+            let cert = args
+                .cert
+                .clone()
+                .or(file.cert)
+                .ok_or_else(|| missing("cert", "--cert", "FT_SERVER_CERT"))?;
+            let key = args
+                .key
+                .clone()
+                .or(file.key)
+                .ok_or_else(|| missing("key", "--key", "FT_SERVER_KEY"))?;
+            let ca_cert = args
+                .ca_cert
+                .clone()
+                .or(file.ca_cert)
+                .ok_or_else(|| missing("ca_cert", "--ca-cert", "FT_SERVER_CA_CERT"))?;
+            (Some(cert), Some(key), Some(ca_cert))
+        };
+
+        let file_log = file.log.unwrap_or_default();
+        let file_log_level = file_log
+            .level
+            .map(|level| {
+                level
+                    .parse()
+                    .map_err(|_| anyhow!("configuration file: invalid log level {level:?}"))
+            })
+            .transpose()?;
+        let log = LogConfig {
+            level: args
+                .verbose
+                .or(file_log_level)
+                .unwrap_or(LogConfig::DEFAULT_LEVEL),
+            format: args.log_format.or(file_log.format).unwrap_or_default(),
+            path: args.log_file.clone().or(file_log.path),
+            max_size: args
+                .log_file_max_size
+                .or(file_log.max_size)
+                .unwrap_or(LogConfig::DEFAULT_MAX_SIZE),
+            max_files: args
+                .log_file_max_files
+                .or(file_log.max_files)
+                .unwrap_or(LogConfig::DEFAULT_MAX_FILES),
+        };
+
+        let file_audit_log = file.audit_log.unwrap_or_default();
+        let audit_log = args
+            .audit_log
+            .clone()
+            .or(file_audit_log.path)
+            .map(|path| AuditLogConfig {
+                path,
+                max_size: args
+                    .audit_log_max_size
+                    .or(file_audit_log.max_size)
+                    .unwrap_or(Self::DEFAULT_AUDIT_LOG_MAX_SIZE),
+                max_files: args
+                    .audit_log_max_files
+                    .or(file_audit_log.max_files)
+                    .unwrap_or(Self::DEFAULT_AUDIT_LOG_MAX_FILES),
+            });
+
+        let config = Self {
+            directory,
+            address,
+            port,
+            listen,
+            activated,
+            insecure,
+            cert,
+            key,
+            ca_cert,
+            tokens: if args.tokens.is_empty() {
+                file.tokens.unwrap_or_default()
+            } else {
+                args.tokens.clone()
+            },
+            mode: args.mode.or(file.mode).unwrap_or_default(),
+            max_file_size: args.max_file_size.or(file.max_file_size),
+            min_free_space: args
+                .min_free_space
+                .or(file.min_free_space)
+                .unwrap_or(Self::DEFAULT_MIN_FREE_SPACE),
+            xattrs: args.xattrs.or(file.xattrs).unwrap_or(false),
+            xattr_namespaces: if args.xattr_namespaces.is_empty() {
+                file.xattr_namespaces
+                    .unwrap_or_else(|| vec![Self::DEFAULT_XATTR_NAMESPACE.to_string()])
+            } else {
+                args.xattr_namespaces.clone()
+            },
+            max_concurrent_requests: args
+                .max_concurrent_requests
+                .or(file.max_concurrent_requests),
+            max_concurrent_requests_per_peer: args
+                .max_concurrent_requests_per_peer
+                .or(file.max_concurrent_requests_per_peer),
+            max_requests_per_second: args
+                .max_requests_per_second
+                .or(file.max_requests_per_second),
+            max_requests_per_second_per_peer: args
+                .max_requests_per_second_per_peer
+                .or(file.max_requests_per_second_per_peer),
+            request_timeout: args.request_timeout.or(file.request_timeout),
+            idle_timeout: args
+                .idle_timeout
+                .or(file.idle_timeout)
+                .unwrap_or(Self::DEFAULT_IDLE_TIMEOUT),
+            keepalive_interval: args.keepalive_interval.or(file.keepalive_interval),
+            keepalive_timeout: args.keepalive_timeout.or(file.keepalive_timeout),
+            log,
+            audit_log,
+            shares,
+        };
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(directory) = &self.directory {
+            if !directory.is_dir() {
+                bail!("directory {directory:?} does not exist");
+            }
+        }
+
+        if self.directory.is_some() && self.shares.contains_key(Shares::DEFAULT_SHARE) {
+            bail!(
+                "share {:?} conflicts with `directory`",
+                Shares::DEFAULT_SHARE
+            );
+        }
+
+        for (name, share) in &self.shares {
+            if name.is_empty() || name.contains(':') {
+                bail!("invalid share name {name:?}");
+            }
+            if !share.directory.is_dir() {
+                bail!(
+                    "directory {:?} of share {name:?} does not exist",
+                    share.directory
+                );
+            }
+        }
+
+        for path in [&self.cert, &self.key, &self.ca_cert].into_iter().flatten() {
+            if !path.is_file() {
+                bail!("TLS file {:?} does not exist", path);
+            }
+        }
+
+        if self.tokens.iter().any(|token| token.is_empty()) {
+            bail!("tokens must not be empty");
+        }
+
+        if self.log.max_size == 0 {
+            bail!("log max_size must be greater than 0");
+        }
+
+        if let Some(audit_log) = &self.audit_log {
+            if audit_log.max_size == 0 {
+                bail!("audit log max_size must be greater than 0");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read configuration file {path:?}"))?;
+
+        toml::from_str(&content).with_context(|| format!("invalid configuration file {path:?}"))
+    }
+}
+
+fn missing(key: &str, flag: &str, env: &str) -> anyhow::Error {
+    anyhow!("missing `{key}`: set {flag}, {env} or `{key}` in the configuration file")
+}
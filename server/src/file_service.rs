@@ -1,91 +1,273 @@
+use crate::archive;
+use crate::audit::{AuditEvent, AuditLog, Operation, Peer};
+use crate::list_filter::ListFilter;
+use crate::share::{Share, Shares};
+use crate::timeouts::{next_message, send_message};
+use crate::upload_limits::{UploadGuard, UploadLimits};
 use anyhow::anyhow;
+use common::attributes::{self, XattrNamespaces};
+use common::delta::{self, DeltaDecoder, DeltaOp};
+use common::sparse::{self, HoleWrite, Piece, SparseReader};
+use common::temp_file::{self, TempFile};
 use proto::api::file_service_server::FileService;
 use proto::api::{
-    upload_file_request, DownloadFileRequest, DownloadFileResponse, ListFilesRequest,
-    ListFilesResponse, UploadFileRequest, UploadFileResponse,
+    download_delta_request, download_delta_response, upload_delta_request, CopyBlocks,
+    DownloadDeltaRequest, DownloadDeltaResponse, GetSignaturesRequest, GetSignaturesResponse,
+    UploadDeltaHeader, UploadDeltaRequest, UploadDeltaResponse,
 };
-use std::path::PathBuf;
+use proto::api::{
+    download_file_response, upload_archive_request, upload_file_request, DownloadArchiveRequest,
+    DownloadArchiveResponse, DownloadFileHeader, DownloadFileRequest, DownloadFileResponse,
+    GetCapabilitiesRequest, GetCapabilitiesResponse, ListFilesRequest, ListFilesResponse,
+    ListSharesRequest, ListSharesResponse, UploadArchiveRequest, UploadArchiveResponse,
+    UploadFileRequest, UploadFileResponse,
+};
+use proto::api::{DeleteFileRequest, DeleteFileResponse, ListTreeRequest, ListTreeResponse};
+use std::fs::Metadata;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
 use tokio::fs;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
-use tokio_stream::StreamExt;
 use tonic::{Request, Response, Status, Streaming};
 use tracing::{error, instrument, Instrument};
 
 #[derive(Default)]
 pub struct FileServiceImpl {
-    directory: Arc<PathBuf>,
+    shares: Arc<Shares>,
+    upload_limits: UploadLimits,
+    /// Namespaces of transferred extended attributes, `None` if disabled
+    xattr_namespaces: Option<XattrNamespaces>,
+    /// Longest time a stream may go without any message
+    idle_timeout: Option<Duration>,
+    audit_log: Option<Arc<AuditLog>>,
 }
 
 impl FileServiceImpl {
     const CHANNEL_SIZE: usize = 10;
     const CHUNK_SIZE_BYTES: u64 = 1024 * 1024; // 1 MB
+    const SIGNATURE_BATCH_SIZE: usize = 4096;
 
-    pub fn new(directory: PathBuf) -> Self {
+    pub fn new(
+        shares: Shares,
+        upload_limits: UploadLimits,
+        xattr_namespaces: Option<XattrNamespaces>,
+        idle_timeout: Option<Duration>,
+        audit_log: Option<AuditLog>,
+    ) -> Self {
         Self {
-            directory: Arc::new(directory),
+            shares: Arc::new(shares),
+            upload_limits,
+            xattr_namespaces,
+            idle_timeout,
+            audit_log: audit_log.map(Arc::new),
+        }
+    }
+
+    /// Looks up share and file in it, updating audited path with the share name.
+    fn resolve(
+        &self,
+        share: &str,
+        name: &str,
+        audit_event: &mut AuditEvent,
+    ) -> Result<(Arc<Share>, PathBuf), Status> {
+        let share = self.shares.get(share, audit_event.peer())?;
+        audit_event.path = format!("{}:{}", share.name, name);
+        let file_path = share.resolve(name)?;
+
+        Ok((share, file_path))
+    }
+
+    /// Looks up share and directory in it, empty name being the whole share.
+    fn resolve_directory(
+        &self,
+        share: &str,
+        name: &str,
+        audit_event: &mut AuditEvent,
+    ) -> Result<(Arc<Share>, PathBuf), Status> {
+        let share = self.shares.get(share, audit_event.peer())?;
+        audit_event.path = format!("{}:{}", share.name, name);
+        let directory = share.resolve_directory(name)?;
+
+        Ok((share, directory))
+    }
+
+    /// Namespaces of extended attributes, failing if they are disabled.
+    fn check_xattrs(&self) -> Result<XattrNamespaces, Status> {
+        self.xattr_namespaces.clone().ok_or_else(|| {
+            Status::failed_precondition("Extended attributes are disabled on the server")
+        })
+    }
+
+    fn audit_event<T>(
+        &self,
+        request: &Request<T>,
+        operation: Operation,
+        path: String,
+    ) -> AuditEvent {
+        AuditEvent::new(
+            self.audit_log.clone(),
+            Peer::from_request(request),
+            operation,
+            path,
+        )
+    }
+}
+
+/// Converts task error to status returned to the client, passing through
+/// statuses raised on purpose and hiding details of internal errors.
+fn into_status(err: anyhow::Error, message: &str) -> Status {
+    match err.downcast::<Status>() {
+        Ok(status) => status,
+        Err(err) => {
+            error!(%err);
+            Status::internal(message)
         }
     }
 }
 
+/// Header of a downloaded file with its attributes, and extended
+/// attributes if they were requested.
+async fn download_header(
+    file_path: &Path,
+    metadata: &Metadata,
+    xattr_namespaces: Option<&XattrNamespaces>,
+) -> anyhow::Result<DownloadFileHeader> {
+    Ok(DownloadFileHeader {
+        size: metadata.len(),
+        attributes: Some(attributes::from_metadata(metadata)),
+        xattrs: match xattr_namespaces {
+            Some(namespaces) => attributes::read_xattrs(file_path, namespaces).await?,
+            None => vec![],
+        },
+    })
+}
+
+/// Passes errors of delta transfers caused by the client through as
+/// invalid argument.
+fn delta_error(err: io::Error) -> anyhow::Error {
+    match err.kind() {
+        ErrorKind::InvalidInput | ErrorKind::InvalidData => {
+            Status::invalid_argument(err.to_string()).into()
+        }
+        _ => err.into(),
+    }
+}
+
+/// Creates missing parent directories of an uploaded file, which mustn't
+/// lead out of the share through symbolic links.
+async fn create_parent(share: &Share, file_path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = file_path.parent() {
+        archive::check_directory(share, parent).await?;
+        fs::create_dir_all(parent).await?;
+    }
+
+    Ok(())
+}
+
+/// Metadata of a file which the receiver of a delta transfer must have.
+async fn existing_file(file_path: &Path, name: &str) -> anyhow::Result<Metadata> {
+    match fs::metadata(file_path).await {
+        Ok(metadata) if metadata.is_file() => Ok(metadata),
+        _ => Err(Status::not_found(format!("File {name:?} not found")))?,
+    }
+}
+
 #[tonic::async_trait]
 impl FileService for FileServiceImpl {
     type DownloadFileStream = ReceiverStream<Result<DownloadFileResponse, Status>>;
     type ListFilesStream = ReceiverStream<Result<ListFilesResponse, Status>>;
+    type DownloadArchiveStream = ReceiverStream<Result<DownloadArchiveResponse, Status>>;
+    type GetSignaturesStream = ReceiverStream<Result<GetSignaturesResponse, Status>>;
+    type DownloadDeltaStream = ReceiverStream<Result<DownloadDeltaResponse, Status>>;
+    type ListTreeStream = ReceiverStream<Result<ListTreeResponse, Status>>;
 
     #[instrument(skip(self))]
     async fn download_file(
         &self,
         request: Request<DownloadFileRequest>,
     ) -> Result<Response<Self::DownloadFileStream>, Status> {
+        let mut audit_event = self.audit_event(
+            &request,
+            Operation::Download,
+            format!("{}:{}", request.get_ref().share, request.get_ref().name),
+        );
         let request = request.into_inner();
+
+        let file_path = match self
+            .resolve(&request.share, &request.name, &mut audit_event)
+            .and_then(|(share, file_path)| share.check_read().map(|_| file_path))
+        {
+            Ok(file_path) => file_path,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+
+        let xattr_namespaces = match request.xattrs.then(|| self.check_xattrs()).transpose() {
+            Ok(xattr_namespaces) => xattr_namespaces,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+
+        let offset = request.offset;
+        let idle_timeout = self.idle_timeout;
         let (tx, rx) = mpsc::channel(Self::CHANNEL_SIZE);
         let tx_error = tx.clone();
-        let directory = Arc::clone(&self.directory);
-
-        let mut file_path = PathBuf::new();
-        file_path.push(directory.as_ref());
-        file_path.push(request.name);
 
         tokio::spawn(
             async move {
-                let result = async move {
-                    let file = fs::File::open(file_path).await?;
-                    let mut handle = file.take(Self::CHUNK_SIZE_BYTES);
+                let result = async {
+                    let file = fs::File::open(&file_path).await?;
+                    let metadata = file.metadata().await?;
+                    let size = metadata.len();
+                    if offset > size {
+                        Err(Status::out_of_range("Offset is beyond end of file"))?;
+                    }
 
-                    loop {
-                        let mut response = DownloadFileResponse {
-                            chunk: Vec::with_capacity(Self::CHUNK_SIZE_BYTES as usize),
-                        };
+                    let header =
+                        download_header(&file_path, &metadata, xattr_namespaces.as_ref()).await?;
+                    let response = DownloadFileResponse {
+                        r#type: Some(download_file_response::Type::Header(header)),
+                    };
+                    if let Err(err) = send_message(&tx, Ok(response), idle_timeout).await {
+                        error!(%err);
+                        Err(err)?;
+                    }
 
-                        let n = handle.read_to_end(&mut response.chunk).await?;
+                    // Holes of sparse files are sent by length instead of as zeros
+                    let mut reader = SparseReader::new(file, offset, size).await?;
 
-                        if 0 == n {
-                            break;
-                        } else {
-                            handle.set_limit(Self::CHUNK_SIZE_BYTES);
-                        }
+                    while let Some(piece) = reader.next(Self::CHUNK_SIZE_BYTES).await? {
+                        let (message, n) = match piece {
+                            Piece::Data(chunk) => {
+                                let n = chunk.len() as u64;
+                                (download_file_response::Type::Chunk(chunk), n)
+                            }
+                            Piece::Hole(len) => (download_file_response::Type::Hole(len), len),
+                        };
+
+                        let response = DownloadFileResponse {
+                            r#type: Some(message),
+                        };
 
-                        if let Err(err) = tx.send(Ok(response)).await {
+                        if let Err(err) = send_message(&tx, Ok(response), idle_timeout).await {
                             error!(%err);
-                            break;
+                            Err(err)?;
                         }
 
-                        if n < Self::CHUNK_SIZE_BYTES as usize {
-                            break;
-                        }
+                        audit_event.bytes += n;
                     }
 
                     Ok::<(), anyhow::Error>(())
                 }
                 .await;
 
+                audit_event.finish(&result).await;
+
                 if let Err(err) = result {
-                    error!(%err);
                     let send_result = tx_error
-                        .send(Err(Status::internal("Failed to send file")))
+                        .send(Err(into_status(err, "Failed to send file")))
                         .await;
 
                     if let Err(err) = send_result {
@@ -104,42 +286,100 @@ impl FileService for FileServiceImpl {
         &self,
         request: Request<Streaming<UploadFileRequest>>,
     ) -> Result<Response<UploadFileResponse>, Status> {
+        let mut audit_event = self.audit_event(&request, Operation::Upload, String::new());
         let mut request_stream = request.into_inner();
-        let directory = Arc::clone(&self.directory);
 
-        let task_handle = tokio::spawn(async move {
-            let file_name = if let Some(file_upload) = request_stream.next().await {
-                match file_upload?.r#type.unwrap() {
-                    upload_file_request::Type::Name(name) => name,
-                    wrong_type => Err(anyhow!("Wrong message type: {:?}", wrong_type))?,
-                }
-            } else {
-                Err(anyhow!("Wrong message type"))?
-            };
+        let header = match next_message(&mut request_stream, self.idle_timeout).await {
+            Ok(header) => header,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+        let header = match header {
+            Some(Ok(UploadFileRequest {
+                r#type: Some(upload_file_request::Type::Header(header)),
+            })) => header,
+            Some(Err(status)) => return Err(audit_event.reject(status).await),
+            _ => {
+                let status = Status::invalid_argument("Upload must start with file header");
+                return Err(audit_event.reject(status).await);
+            }
+        };
+        audit_event.path = format!("{}:{}", header.share, header.name);
+
+        let (share, file_path) = match self
+            .resolve(&header.share, &header.name, &mut audit_event)
+            .and_then(|(share, file_path)| share.check_write().map(|_| (share, file_path)))
+        {
+            Ok(resolved) => resolved,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+
+        let xattr_namespaces = if header.xattrs.is_empty() {
+            None
+        } else {
+            match self.check_xattrs() {
+                Ok(xattr_namespaces) => Some(xattr_namespaces),
+                Err(status) => return Err(audit_event.reject(status).await),
+            }
+        };
+
+        let limits = self.upload_limits;
+        let idle_timeout = self.idle_timeout;
 
-            let mut file_path = PathBuf::new();
-            file_path.push(directory.as_ref());
-            file_path.push(&file_name);
+        let task_handle = tokio::spawn(
+            async move {
+                let result = async {
+                    let guard = UploadGuard::new(limits, &share, &file_path, header.size).await?;
+
+                    create_parent(&share, &file_path).await?;
+                    // Previous version stays in place until the upload completes
+                    let (temp_file, mut file_handle) = TempFile::create(&file_path).await?;
+
+                    while let Some(file_upload) =
+                        next_message(&mut request_stream, idle_timeout).await?
+                    {
+                        match file_upload?.r#type {
+                            Some(upload_file_request::Type::Chunk(chunk)) => {
+                                guard
+                                    .check(audit_event.bytes, chunk.len() as u64, &share.name)
+                                    .await?;
+                                file_handle.write_all(&chunk).await?;
+                                audit_event.bytes += chunk.len() as u64;
+                            }
+                            Some(upload_file_request::Type::Hole(len)) => {
+                                guard.check_hole(audit_event.bytes, len, &share.name)?;
+                                file_handle.write_hole(len).await?;
+                                audit_event.bytes += len;
+                            }
+                            wrong_type => Err(anyhow!("Wrong message type: {:?}", wrong_type))?,
+                        }
+                    }
 
-            let mut file_handle = fs::File::create(file_path).await?;
+                    sparse::finish_holes(&mut file_handle).await?;
+                    file_handle.sync_all().await?;
 
-            while let Some(file_upload) = request_stream.next().await {
-                match file_upload?.r#type {
-                    Some(upload_file_request::Type::Chunk(chunk)) => {
-                        file_handle.write_all(&chunk).await?;
+                    // Before permissions, which could make the file read-only
+                    if let Some(namespaces) = &xattr_namespaces {
+                        attributes::apply_xattrs(temp_file.path(), header.xattrs, namespaces)
+                            .await?;
+                    }
+                    if let Some(file_attributes) = &header.attributes {
+                        attributes::apply(temp_file.path(), file_attributes).await?;
                     }
-                    wrong_type => Err(anyhow!("Wrong message type: {:?}", wrong_type))?,
+                    temp_file.persist().await?;
+
+                    Ok::<(), anyhow::Error>(())
                 }
-            }
+                .await;
 
-            file_handle.sync_all().await?;
+                audit_event.finish(&result).await;
 
-            Ok::<(), anyhow::Error>(())
-        });
+                result
+            }
+            .in_current_span(),
+        );
 
         if let Err(err) = task_handle.await.unwrap() {
-            error!(%err);
-            Err(Status::internal("Failed to upload file"))
+            Err(into_status(err, "Failed to upload file"))
         } else {
             Ok(Response::new(UploadFileResponse::default()))
         }
@@ -148,34 +388,90 @@ impl FileService for FileServiceImpl {
     #[instrument(skip(self))]
     async fn list_files(
         &self,
-        _request: Request<ListFilesRequest>,
+        request: Request<ListFilesRequest>,
     ) -> Result<Response<Self::ListFilesStream>, Status> {
+        let mut audit_event = self.audit_event(
+            &request,
+            Operation::List,
+            format!("{}:", request.get_ref().share),
+        );
+        let request = request.into_inner();
+
+        let share = match self
+            .shares
+            .get(&request.share, audit_event.peer())
+            .and_then(|share| {
+                audit_event.path = format!("{}:", share.name);
+                share.check_read().map(|_| share)
+            }) {
+            Ok(share) => share,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+
+        let filter = match ListFilter::new(&request) {
+            Ok(filter) => filter,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+
+        let idle_timeout = self.idle_timeout;
         let (tx, rx) = mpsc::channel(Self::CHANNEL_SIZE);
-        let directory = Arc::clone(&self.directory);
         let tx_error = tx.clone();
 
         tokio::spawn(
             async move {
-                let result = async move {
-                    let mut dir_stream = fs::read_dir(directory.as_ref()).await?;
+                let result = async {
+                    let mut dir_stream = fs::read_dir(&share.directory).await?;
+                    let mut sorted_files = filter.sorted_files();
+                    let mut sent = 0;
 
                     while let Some(dir_entry) = dir_stream.next_entry().await? {
                         let file_metadata = dir_entry.metadata().await?;
-                        if !file_metadata.is_file() {
+                        if !file_metadata.is_file() || temp_file::is_temp(&dir_entry.file_name()) {
                             continue;
                         }
                         let file_name = dir_entry.file_name().into_string().map_err(|e| {
                             anyhow!("OsString convertion failed: {:?}", e.to_string_lossy())
                         })?;
                         let file_size = file_metadata.len();
+                        let modified = file_metadata
+                            .modified()
+                            .ok()
+                            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                            .map_or(0, |modified| modified.as_secs());
 
-                        if let Err(err) = tx
-                            .send(Ok(ListFilesResponse {
-                                name: file_name,
-                                size: file_size,
-                            }))
-                            .await
-                        {
+                        let file = ListFilesResponse {
+                            name: file_name,
+                            size: file_size,
+                            modified,
+                            ..Default::default()
+                        };
+
+                        if !filter.matches(&file) {
+                            continue;
+                        }
+                        if filter.needs_sorting() {
+                            sorted_files.push(file);
+                            continue;
+                        }
+
+                        if let Err(err) = send_message(&tx, Ok(file), idle_timeout).await {
+                            error!(%err);
+                            break;
+                        }
+
+                        sent += 1;
+                        if matches!(filter.limit(), Some(limit) if sent >= limit) {
+                            break;
+                        }
+                    }
+
+                    let (mut sorted_files, next_page_token) = sorted_files.finish();
+                    if let (Some(token), Some(last)) = (next_page_token, sorted_files.last_mut()) {
+                        last.next_page_token = token;
+                    }
+
+                    for file in sorted_files {
+                        if let Err(err) = send_message(&tx, Ok(file), idle_timeout).await {
                             error!(%err);
                             break;
                         }
@@ -185,10 +481,11 @@ impl FileService for FileServiceImpl {
                 }
                 .await;
 
+                audit_event.finish(&result).await;
+
                 if let Err(err) = result {
-                    error!(%err);
                     let send_result = tx_error
-                        .send(Err(Status::internal("Failed to list files")))
+                        .send(Err(into_status(err, "Failed to list files")))
                         .await;
 
                     if let Err(err) = send_result {
@@ -201,4 +498,695 @@ impl FileService for FileServiceImpl {
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
+
+    #[instrument(skip(self))]
+    async fn list_shares(
+        &self,
+        request: Request<ListSharesRequest>,
+    ) -> Result<Response<ListSharesResponse>, Status> {
+        let peer = Peer::from_request(&request);
+
+        let shares = self
+            .shares
+            .iter()
+            .filter(|share| share.is_allowed(&peer))
+            .map(|share| share.info())
+            .collect();
+
+        Ok(Response::new(ListSharesResponse { shares }))
+    }
+
+    #[instrument(skip(self))]
+    async fn get_capabilities(
+        &self,
+        request: Request<GetCapabilitiesRequest>,
+    ) -> Result<Response<GetCapabilitiesResponse>, Status> {
+        let peer = Peer::from_request(&request);
+        let share = self.shares.get(&request.get_ref().share, &peer)?;
+
+        Ok(Response::new(share.capabilities()))
+    }
+
+    #[instrument(skip(self))]
+    async fn download_archive(
+        &self,
+        request: Request<DownloadArchiveRequest>,
+    ) -> Result<Response<Self::DownloadArchiveStream>, Status> {
+        let mut audit_event = self.audit_event(
+            &request,
+            Operation::DownloadArchive,
+            format!("{}:{}", request.get_ref().share, request.get_ref().name),
+        );
+        let request = request.into_inner();
+        let format = request.format();
+
+        let (share, directory) = match self
+            .resolve_directory(&request.share, &request.name, &mut audit_event)
+            .and_then(|(share, directory)| share.check_read().map(|_| (share, directory)))
+        {
+            Ok(resolved) => resolved,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+
+        let idle_timeout = self.idle_timeout;
+        let (tx, rx) = mpsc::channel(Self::CHANNEL_SIZE);
+        let tx_error = tx.clone();
+
+        tokio::spawn(
+            async move {
+                let result = async {
+                    if !matches!(fs::metadata(&directory).await, Ok(metadata) if metadata.is_dir())
+                    {
+                        Err(Status::not_found(format!(
+                            "Directory {:?} not found",
+                            request.name
+                        )))?;
+                    }
+
+                    // Archive is built into a pipe and sent in chunks as it grows
+                    let (writer, mut reader) = tokio::io::duplex(Self::CHUNK_SIZE_BYTES as usize);
+
+                    let send = async {
+                        let mut handle = (&mut reader).take(Self::CHUNK_SIZE_BYTES);
+
+                        loop {
+                            let mut chunk = Vec::with_capacity(Self::CHUNK_SIZE_BYTES as usize);
+
+                            let n = handle.read_to_end(&mut chunk).await?;
+
+                            if 0 == n {
+                                break;
+                            } else {
+                                handle.set_limit(Self::CHUNK_SIZE_BYTES);
+                            }
+
+                            let response = DownloadArchiveResponse { chunk };
+
+                            if let Err(err) = send_message(&tx, Ok(response), idle_timeout).await {
+                                error!(%err);
+                                Err(err)?;
+                            }
+
+                            audit_event.bytes += n as u64;
+                        }
+
+                        Ok::<(), anyhow::Error>(())
+                    };
+
+                    tokio::try_join!(
+                        archive::write_archive(&share, &directory, format, writer),
+                        send
+                    )?;
+
+                    Ok::<(), anyhow::Error>(())
+                }
+                .await;
+
+                audit_event.finish(&result).await;
+
+                if let Err(err) = result {
+                    let send_result = tx_error
+                        .send(Err(into_status(err, "Failed to send archive")))
+                        .await;
+
+                    if let Err(err) = send_result {
+                        error!(%err);
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    #[instrument(skip(self))]
+    async fn upload_archive(
+        &self,
+        request: Request<Streaming<UploadArchiveRequest>>,
+    ) -> Result<Response<UploadArchiveResponse>, Status> {
+        let mut audit_event = self.audit_event(&request, Operation::UploadArchive, String::new());
+        let mut request_stream = request.into_inner();
+
+        let header = match next_message(&mut request_stream, self.idle_timeout).await {
+            Ok(header) => header,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+        let header = match header {
+            Some(Ok(UploadArchiveRequest {
+                r#type: Some(upload_archive_request::Type::Header(header)),
+            })) => header,
+            Some(Err(status)) => return Err(audit_event.reject(status).await),
+            _ => {
+                let status = Status::invalid_argument("Upload must start with archive header");
+                return Err(audit_event.reject(status).await);
+            }
+        };
+        audit_event.path = format!("{}:{}", header.share, header.name);
+        let format = header.format();
+
+        let (share, directory) = match self
+            .resolve_directory(&header.share, &header.name, &mut audit_event)
+            .and_then(|(share, directory)| share.check_write().map(|_| (share, directory)))
+        {
+            Ok(resolved) => resolved,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+
+        let limits = self.upload_limits;
+        let idle_timeout = self.idle_timeout;
+
+        let task_handle = tokio::spawn(
+            async move {
+                let mut files = 0;
+                let result = async {
+                    // Received chunks are piped into the extraction as they arrive
+                    let (mut writer, mut reader) =
+                        tokio::io::duplex(Self::CHUNK_SIZE_BYTES as usize);
+
+                    let receive = async {
+                        while let Some(archive_upload) =
+                            next_message(&mut request_stream, idle_timeout).await?
+                        {
+                            match archive_upload?.r#type {
+                                Some(upload_archive_request::Type::Chunk(chunk)) => {
+                                    writer.write_all(&chunk).await?;
+                                }
+                                wrong_type => Err(anyhow!("Wrong message type: {:?}", wrong_type))?,
+                            }
+                        }
+
+                        writer.shutdown().await?;
+
+                        Ok::<(), anyhow::Error>(())
+                    };
+
+                    let extract = async {
+                        let extracted = archive::extract_archive(
+                            &mut reader,
+                            format,
+                            &share,
+                            &directory,
+                            limits,
+                        )
+                        .await?;
+                        // Archives may be padded past their last entry
+                        tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?;
+
+                        Ok::<_, anyhow::Error>(extracted)
+                    };
+
+                    let ((), extracted) = tokio::try_join!(receive, extract)?;
+                    audit_event.bytes = extracted.bytes;
+                    files = extracted.files;
+
+                    Ok::<(), anyhow::Error>(())
+                }
+                .await;
+
+                audit_event.finish(&result).await;
+
+                result.map(|()| files)
+            }
+            .in_current_span(),
+        );
+
+        match task_handle.await.unwrap() {
+            Ok(files) => Ok(Response::new(UploadArchiveResponse { files })),
+            Err(err) => Err(into_status(err, "Failed to extract archive")),
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn get_signatures(
+        &self,
+        request: Request<GetSignaturesRequest>,
+    ) -> Result<Response<Self::GetSignaturesStream>, Status> {
+        let mut audit_event = self.audit_event(
+            &request,
+            Operation::Signatures,
+            format!("{}:{}", request.get_ref().share, request.get_ref().name),
+        );
+        let request = request.into_inner();
+
+        let file_path = match self
+            .resolve(&request.share, &request.name, &mut audit_event)
+            .and_then(|(share, file_path)| share.check_read().map(|_| file_path))
+        {
+            Ok(file_path) => file_path,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+
+        let idle_timeout = self.idle_timeout;
+        let (tx, rx) = mpsc::channel(Self::CHANNEL_SIZE);
+        let tx_error = tx.clone();
+
+        tokio::spawn(
+            async move {
+                let result = async {
+                    let metadata = existing_file(&file_path, &request.name).await?;
+                    let block_size = delta::block_size(metadata.len());
+                    let signatures = delta::signatures(&file_path, block_size).await?;
+
+                    // Empty file still tells the block size
+                    let mut batches = signatures.chunks(Self::SIGNATURE_BATCH_SIZE).peekable();
+                    if batches.peek().is_none() {
+                        let response = GetSignaturesResponse {
+                            block_size,
+                            blocks: vec![],
+                        };
+                        send_message(&tx, Ok(response), idle_timeout).await?;
+                    }
+
+                    for blocks in batches {
+                        let response = GetSignaturesResponse {
+                            block_size,
+                            blocks: blocks.to_vec(),
+                        };
+                        if let Err(err) = send_message(&tx, Ok(response), idle_timeout).await {
+                            error!(%err);
+                            Err(err)?;
+                        }
+                    }
+
+                    Ok::<(), anyhow::Error>(())
+                }
+                .await;
+
+                audit_event.finish(&result).await;
+
+                if let Err(err) = result {
+                    let send_result = tx_error
+                        .send(Err(into_status(err, "Failed to send signatures")))
+                        .await;
+
+                    if let Err(err) = send_result {
+                        error!(%err);
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    #[instrument(skip(self))]
+    async fn upload_delta(
+        &self,
+        request: Request<Streaming<UploadDeltaRequest>>,
+    ) -> Result<Response<UploadDeltaResponse>, Status> {
+        let mut audit_event = self.audit_event(&request, Operation::UploadDelta, String::new());
+        let mut request_stream = request.into_inner();
+
+        let header = match next_message(&mut request_stream, self.idle_timeout).await {
+            Ok(header) => header,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+        let (header, block_size) = match header {
+            Some(Ok(UploadDeltaRequest {
+                r#type:
+                    Some(upload_delta_request::Type::Header(UploadDeltaHeader {
+                        file: Some(header),
+                        block_size,
+                    })),
+            })) => (header, block_size),
+            Some(Err(status)) => return Err(audit_event.reject(status).await),
+            _ => {
+                let status = Status::invalid_argument("Upload must start with file header");
+                return Err(audit_event.reject(status).await);
+            }
+        };
+        audit_event.path = format!("{}:{}", header.share, header.name);
+
+        let (share, file_path) = match self
+            .resolve(&header.share, &header.name, &mut audit_event)
+            .and_then(|(share, file_path)| share.check_write().map(|_| (share, file_path)))
+        {
+            Ok(resolved) => resolved,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+
+        if header.size.is_none() {
+            let status = Status::invalid_argument("Delta upload must declare file size");
+            return Err(audit_event.reject(status).await);
+        }
+        if let Err(err) = delta::check_block_size(block_size) {
+            let status = Status::invalid_argument(err.to_string());
+            return Err(audit_event.reject(status).await);
+        }
+
+        let xattr_namespaces = if header.xattrs.is_empty() {
+            None
+        } else {
+            match self.check_xattrs() {
+                Ok(xattr_namespaces) => Some(xattr_namespaces),
+                Err(status) => return Err(audit_event.reject(status).await),
+            }
+        };
+
+        let limits = self.upload_limits;
+        let idle_timeout = self.idle_timeout;
+
+        let task_handle = tokio::spawn(
+            async move {
+                let mut sent = 0;
+                let result = async {
+                    existing_file(&file_path, &header.name).await?;
+                    let guard = UploadGuard::new(limits, &share, &file_path, header.size).await?;
+
+                    // New version is rebuilt next to the old one, which it replaces
+                    let mut decoder = DeltaDecoder::new(&file_path, block_size)
+                        .await
+                        .map_err(delta_error)?;
+
+                    let mut checksum = None;
+
+                    while let Some(delta_upload) =
+                        next_message(&mut request_stream, idle_timeout).await?
+                    {
+                        let op = match delta_upload?.r#type {
+                            Some(upload_delta_request::Type::Copy(copy)) => DeltaOp::Copy {
+                                index: copy.index,
+                                count: copy.count,
+                            },
+                            Some(upload_delta_request::Type::Data(data)) => {
+                                sent += data.len() as u64;
+                                DeltaOp::Data(data)
+                            }
+                            Some(upload_delta_request::Type::Checksum(sum)) => {
+                                checksum = Some(sum);
+                                continue;
+                            }
+                            wrong_type => Err(anyhow!("Wrong message type: {:?}", wrong_type))?,
+                        };
+
+                        let len = decoder.op_len(&op).map_err(delta_error)?;
+                        guard.check(decoder.written(), len, &share.name).await?;
+                        decoder.apply(op).await?;
+                    }
+
+                    let checksum = checksum.ok_or_else(|| {
+                        Status::invalid_argument("Delta upload must end with checksum")
+                    })?;
+                    let temp_file = decoder.finish(&checksum).await.map_err(delta_error)?;
+
+                    // Before permissions, which could make the file read-only
+                    if let Some(namespaces) = &xattr_namespaces {
+                        attributes::apply_xattrs(temp_file.path(), header.xattrs, namespaces)
+                            .await?;
+                    }
+                    if let Some(file_attributes) = &header.attributes {
+                        attributes::apply(temp_file.path(), file_attributes).await?;
+                    }
+                    temp_file.persist().await?;
+
+                    Ok::<(), anyhow::Error>(())
+                }
+                .await;
+
+                audit_event.bytes = sent;
+                audit_event.finish(&result).await;
+
+                result.map(|()| sent)
+            }
+            .in_current_span(),
+        );
+
+        match task_handle.await.unwrap() {
+            Ok(sent) => Ok(Response::new(UploadDeltaResponse { sent })),
+            Err(err) => Err(into_status(err, "Failed to upload file")),
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn download_delta(
+        &self,
+        request: Request<Streaming<DownloadDeltaRequest>>,
+    ) -> Result<Response<Self::DownloadDeltaStream>, Status> {
+        let mut audit_event = self.audit_event(&request, Operation::DownloadDelta, String::new());
+        let mut request_stream = request.into_inner();
+
+        let header = match next_message(&mut request_stream, self.idle_timeout).await {
+            Ok(header) => header,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+        let header = match header {
+            Some(Ok(DownloadDeltaRequest {
+                r#type: Some(download_delta_request::Type::Header(header)),
+            })) => header,
+            Some(Err(status)) => return Err(audit_event.reject(status).await),
+            _ => {
+                let status = Status::invalid_argument("Download must start with file header");
+                return Err(audit_event.reject(status).await);
+            }
+        };
+        audit_event.path = format!("{}:{}", header.share, header.name);
+
+        let file_path = match self
+            .resolve(&header.share, &header.name, &mut audit_event)
+            .and_then(|(share, file_path)| share.check_read().map(|_| file_path))
+        {
+            Ok(file_path) => file_path,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+
+        if let Err(err) = delta::check_block_size(header.block_size) {
+            let status = Status::invalid_argument(err.to_string());
+            return Err(audit_event.reject(status).await);
+        }
+
+        let xattr_namespaces = match header.xattrs.then(|| self.check_xattrs()).transpose() {
+            Ok(xattr_namespaces) => xattr_namespaces,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+
+        let block_size = header.block_size;
+        let idle_timeout = self.idle_timeout;
+        let (tx, rx) = mpsc::channel(Self::CHANNEL_SIZE);
+        let tx_error = tx.clone();
+
+        tokio::spawn(
+            async move {
+                let result = async {
+                    // All signatures are needed before any block can be matched
+                    let mut signatures = Vec::new();
+                    while let Some(delta_download) =
+                        next_message(&mut request_stream, idle_timeout).await?
+                    {
+                        match delta_download?.r#type {
+                            Some(download_delta_request::Type::Signatures(batch)) => {
+                                signatures.extend(batch.blocks);
+                            }
+                            wrong_type => Err(anyhow!("Wrong message type: {:?}", wrong_type))?,
+                        }
+                        if signatures.len() > delta::MAX_BLOCK_COUNT {
+                            Err(Status::invalid_argument(format!(
+                                "More than {} block signatures",
+                                delta::MAX_BLOCK_COUNT
+                            )))?;
+                        }
+                    }
+
+                    let file = fs::File::open(&file_path).await?;
+                    let metadata = file.metadata().await?;
+                    let header =
+                        download_header(&file_path, &metadata, xattr_namespaces.as_ref()).await?;
+                    let response = DownloadDeltaResponse {
+                        r#type: Some(download_delta_response::Type::Header(header)),
+                    };
+                    if let Err(err) = send_message(&tx, Ok(response), idle_timeout).await {
+                        error!(%err);
+                        Err(err)?;
+                    }
+
+                    let (mut ops, encoder) =
+                        delta::spawn_encoder(file.into_std().await, block_size, signatures);
+
+                    while let Some(op) = ops.recv().await {
+                        let message = match op {
+                            DeltaOp::Copy { index, count } => {
+                                download_delta_response::Type::Copy(CopyBlocks { index, count })
+                            }
+                            DeltaOp::Data(data) => {
+                                audit_event.bytes += data.len() as u64;
+                                download_delta_response::Type::Data(data)
+                            }
+                        };
+                        let response = DownloadDeltaResponse {
+                            r#type: Some(message),
+                        };
+                        if let Err(err) = send_message(&tx, Ok(response), idle_timeout).await {
+                            error!(%err);
+                            Err(err)?;
+                        }
+                    }
+
+                    let response = DownloadDeltaResponse {
+                        r#type: Some(download_delta_response::Type::Checksum(encoder.await??)),
+                    };
+                    if let Err(err) = send_message(&tx, Ok(response), idle_timeout).await {
+                        error!(%err);
+                        Err(err)?;
+                    }
+
+                    Ok::<(), anyhow::Error>(())
+                }
+                .await;
+
+                audit_event.finish(&result).await;
+
+                if let Err(err) = result {
+                    let send_result = tx_error
+                        .send(Err(into_status(err, "Failed to send file")))
+                        .await;
+
+                    if let Err(err) = send_result {
+                        error!(%err);
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    #[instrument(skip(self))]
+    async fn list_tree(
+        &self,
+        request: Request<ListTreeRequest>,
+    ) -> Result<Response<Self::ListTreeStream>, Status> {
+        let mut audit_event = self.audit_event(
+            &request,
+            Operation::ListTree,
+            format!("{}:{}", request.get_ref().share, request.get_ref().name),
+        );
+        let request = request.into_inner();
+
+        let (share, directory) = match self
+            .resolve_directory(&request.share, &request.name, &mut audit_event)
+            .and_then(|(share, directory)| share.check_read().map(|_| (share, directory)))
+        {
+            Ok(resolved) => resolved,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+
+        let idle_timeout = self.idle_timeout;
+        let (tx, rx) = mpsc::channel(Self::CHANNEL_SIZE);
+        let tx_error = tx.clone();
+
+        tokio::spawn(
+            async move {
+                let result = async {
+                    if !matches!(fs::metadata(&directory).await, Ok(metadata) if metadata.is_dir())
+                    {
+                        Err(Status::not_found(format!(
+                            "Directory {:?} not found",
+                            request.name
+                        )))?;
+                    }
+                    archive::check_directory(&share, &directory).await?;
+
+                    for entry in archive::walk(&directory).await? {
+                        if !entry.metadata.is_file() {
+                            continue;
+                        }
+                        let name = entry.name.to_str().ok_or_else(|| {
+                            anyhow!("OsString convertion failed: {:?}", entry.name)
+                        })?;
+                        let modified = entry
+                            .metadata
+                            .modified()
+                            .ok()
+                            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                            .map_or(0, |modified| modified.as_secs());
+                        let checksum = if request.checksums {
+                            delta::checksum(&entry.path).await?
+                        } else {
+                            vec![]
+                        };
+
+                        let file = ListTreeResponse {
+                            name: name.to_string(),
+                            size: entry.metadata.len(),
+                            modified,
+                            checksum,
+                        };
+                        if let Err(err) = send_message(&tx, Ok(file), idle_timeout).await {
+                            error!(%err);
+                            Err(err)?;
+                        }
+                    }
+
+                    Ok::<(), anyhow::Error>(())
+                }
+                .await;
+
+                audit_event.finish(&result).await;
+
+                if let Err(err) = result {
+                    let send_result = tx_error
+                        .send(Err(into_status(err, "Failed to list files")))
+                        .await;
+
+                    if let Err(err) = send_result {
+                        error!(%err);
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    #[instrument(skip(self))]
+    async fn delete_file(
+        &self,
+        request: Request<DeleteFileRequest>,
+    ) -> Result<Response<DeleteFileResponse>, Status> {
+        let mut audit_event = self.audit_event(
+            &request,
+            Operation::Delete,
+            format!("{}:{}", request.get_ref().share, request.get_ref().name),
+        );
+        let request = request.into_inner();
+
+        // Deleting tells whether a file exists, which takes reading the share
+        let (share, file_path) = match self
+            .resolve(&request.share, &request.name, &mut audit_event)
+            .and_then(|(share, file_path)| {
+                share.check_read()?;
+                share.check_write()?;
+                Ok((share, file_path))
+            }) {
+            Ok(resolved) => resolved,
+            Err(status) => return Err(audit_event.reject(status).await),
+        };
+
+        let result = async {
+            if let Some(parent) = file_path.parent() {
+                archive::check_directory(&share, parent).await?;
+            }
+            // Symlinks aren't followed, nor removed in place of their target
+            match fs::symlink_metadata(&file_path).await {
+                Ok(metadata) if metadata.is_file() => fs::remove_file(&file_path).await?,
+                _ => Err(Status::not_found(format!(
+                    "File {:?} not found",
+                    request.name
+                )))?,
+            }
+
+            Ok::<(), anyhow::Error>(())
+        }
+        .await;
+
+        audit_event.finish(&result).await;
+
+        match result {
+            Ok(()) => Ok(Response::new(DeleteFileResponse::default())),
+            Err(err) => Err(into_status(err, "Failed to delete file")),
+        }
+    }
 }
@@ -1,12 +1,34 @@
+// Handlers and their helpers return `tonic::Status`, which is large by design
+#![allow(clippy::result_large_err)]
+
+mod archive;
+mod audit;
+mod auth;
 pub mod cli;
+pub mod config;
 mod file_service;
+mod list_filter;
+pub mod listen;
+mod rate_limit;
+pub mod share;
+pub mod systemd;
+mod timeouts;
+mod upload_limits;
 
-use crate::{cli::Cli, file_service::FileServiceImpl};
-use anyhow::{anyhow, Result};
+use crate::{
+    audit::AuditLog, auth::TokenCheck, config::Config, file_service::FileServiceImpl,
+    listen::Incoming, rate_limit::RateLimitLayer,
+};
+use anyhow::Result;
 use proto::api::file_service_server::FileServiceServer;
-use std::{net::SocketAddr, path::Path};
-use tokio::net::TcpListener;
-use tonic::transport::{server::TcpIncoming, Certificate, Identity, Server, ServerTlsConfig};
+use std::{os::unix::io::OwnedFd, path::Path};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    task::JoinSet,
+};
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tracing::info;
 
 fn create_tls_config(
     cert_path: &Path,
@@ -26,35 +48,114 @@ fn create_tls_config(
     Ok(tls_config)
 }
 
-pub async fn server_main(args: &Cli) -> Result<()> {
-    let socket_addr = SocketAddr::new(args.address, args.port.unwrap_or(0));
-    let listener = TcpListener::bind(socket_addr).await?;
-    let local_addr = listener.local_addr()?;
-    let listener = TcpIncoming::from_listener(listener, true, None).map_err(|e| anyhow!(e))?;
+/// Runs the server on configured listeners and sockets `listen_fds` passed
+/// by systemd, see [`systemd::take_listen_fds`].
+pub async fn server_main(config: &Config, listen_fds: Vec<OwnedFd>) -> Result<()> {
+    let audit_log = match &config.audit_log {
+        Some(audit_log) => Some(AuditLog::new(
+            &audit_log.path,
+            audit_log.max_size,
+            audit_log.max_files,
+        )?),
+        None => None,
+    };
+
+    let timeouts = config.timeouts();
+    let file_service_impl = FileServiceImpl::new(
+        config.shares(),
+        config.upload_limits(),
+        config.xattr_namespaces(),
+        timeouts.idle,
+        audit_log,
+    );
+    let file_service_server = FileServiceServer::with_interceptor(
+        file_service_impl,
+        TokenCheck::new(config.tokens.clone()),
+    );
+    let rate_limit_layer = RateLimitLayer::new(config.rate_limits());
 
-    let file_service_impl = FileServiceImpl::new(args.directory.clone());
-    let file_service_server = FileServiceServer::new(file_service_impl);
+    let mut incomings = Vec::new();
+    for listen_address in config.listeners() {
+        incomings.push(Incoming::bind(listen_address).await?);
+    }
+    for (listen_address, fd) in config.activated_listeners().into_iter().zip(listen_fds) {
+        incomings.push(Incoming::from_fd(listen_address, fd)?);
+    }
 
-    let enable_tls =
-        args.cert.is_some() && args.key.is_some() && args.ca_cert.is_some() && !args.insecure;
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let mut servers = JoinSet::new();
 
-    let mut server = Server::builder();
+    for incoming in incomings {
+        let mut server = timeouts.apply(Server::builder());
+        let mut shutdown_rx = shutdown_rx.clone();
+        let shutdown = async move {
+            let _ = shutdown_rx.changed().await;
+        };
 
-    if enable_tls {
-        let tls_config = create_tls_config(
-            args.cert.as_ref().unwrap(),
-            args.key.as_ref().unwrap(),
-            args.ca_cert.as_ref().unwrap(),
-        )?;
-        server = server.tls_config(tls_config)?;
-    };
+        match incoming {
+            Incoming::Tcp {
+                incoming,
+                local_addr,
+                tls,
+            } => {
+                if tls {
+                    let tls_config = create_tls_config(
+                        config.cert.as_ref().unwrap(),
+                        config.key.as_ref().unwrap(),
+                        config.ca_cert.as_ref().unwrap(),
+                    )?;
+                    server = server.tls_config(tls_config)?;
+                };
+
+                println!("Server address {local_addr}");
+
+                servers.spawn(
+                    server
+                        .layer(rate_limit_layer.clone())
+                        .layer(timeouts.request_timeout_layer())
+                        .add_service(file_service_server.clone())
+                        .serve_with_incoming_shutdown(incoming, shutdown),
+                );
+            }
+            Incoming::Unix { incoming, path } => {
+                println!("Server address unix:{}", path.display());
+
+                servers.spawn(
+                    server
+                        .layer(rate_limit_layer.clone())
+                        .layer(timeouts.request_timeout_layer())
+                        .add_service(file_service_server.clone())
+                        .serve_with_incoming_shutdown(incoming, shutdown),
+                );
+            }
+        }
+    }
+
+    systemd::notify("READY=1")?;
+
+    tokio::select! {
+        Some(result) = servers.join_next() => result??,
+        result = shutdown_signal() => result?,
+    }
+
+    info!("Shutting down");
+    systemd::notify("STOPPING=1")?;
+    let _ = shutdown_tx.send(());
+
+    while let Some(result) = servers.join_next().await {
+        result??;
+    }
+
+    Ok(())
+}
 
-    println!("Server address {local_addr}");
+async fn shutdown_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
 
-    server
-        .add_service(file_service_server)
-        .serve_with_incoming(listener)
-        .await?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
 
     Ok(())
 }
@@ -0,0 +1,401 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use glob::Pattern;
+use proto::api::{ListFilesRequest, ListFilesResponse, SortBy};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use tonic::Status;
+
+/// Selects and orders files returned by `ListFiles`.
+#[derive(Debug)]
+pub struct ListFilter {
+    glob: Option<Pattern>,
+    regex: Option<Regex>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_since: Option<u64>,
+    sort_by: SortBy,
+    reverse: bool,
+    limit: Option<usize>,
+    page_size: Option<usize>,
+    listing: u64,
+    after: Option<PageToken>,
+}
+
+/// Position in a listing, the last file of the previous page. Pages are
+/// stable across calls as they continue after this key, wherever it is in
+/// the directory now. Tokens are only valid for the listing they came from.
+#[derive(Debug, Deserialize, Serialize)]
+struct PageToken {
+    sort_by: i32,
+    reverse: bool,
+    /// Hash of share and filters of the listing
+    listing: u64,
+    name: String,
+    size: u64,
+    modified: u64,
+}
+
+impl PageToken {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(token: &str) -> Option<Self> {
+        let json = URL_SAFE_NO_PAD.decode(token).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    fn file(&self) -> ListFilesResponse {
+        ListFilesResponse {
+            name: self.name.clone(),
+            size: self.size,
+            modified: self.modified,
+            ..Default::default()
+        }
+    }
+}
+
+impl ListFilter {
+    pub fn new(request: &ListFilesRequest) -> Result<Self, Status> {
+        let glob = (!request.glob.is_empty())
+            .then(|| Pattern::new(&request.glob))
+            .transpose()
+            .map_err(|err| Status::invalid_argument(format!("Invalid glob pattern: {err}")))?;
+        let regex = (!request.regex.is_empty())
+            .then(|| Regex::new(&request.regex))
+            .transpose()
+            .map_err(|err| Status::invalid_argument(format!("Invalid regex: {err}")))?;
+
+        let page_size = (request.page_size > 0).then_some(request.page_size as usize);
+        if page_size.is_some() && request.limit > 0 {
+            return Err(Status::invalid_argument(
+                "Limit can't be combined with page size",
+            ));
+        }
+
+        // Pages need a stable order
+        let sort_by = match request.sort_by() {
+            SortBy::None if page_size.is_some() => SortBy::Name,
+            sort_by => sort_by,
+        };
+
+        let listing = listing_hash(request);
+        let after = if request.page_token.is_empty() {
+            None
+        } else {
+            let token = PageToken::decode(&request.page_token)
+                .filter(|_| page_size.is_some())
+                .ok_or_else(|| Status::invalid_argument("Invalid page token"))?;
+            if token.sort_by != sort_by as i32
+                || token.reverse != request.reverse
+                || token.listing != listing
+            {
+                return Err(Status::invalid_argument(
+                    "Page token doesn't match share, filters or sort order of the request",
+                ));
+            }
+            Some(token)
+        };
+
+        Ok(Self {
+            glob,
+            regex,
+            min_size: request.min_size,
+            max_size: request.max_size,
+            modified_since: request.modified_since,
+            sort_by,
+            reverse: request.reverse,
+            limit: (request.limit > 0).then_some(request.limit as usize),
+            page_size,
+            listing,
+            after,
+        })
+    }
+
+    pub fn matches(&self, file: &ListFilesResponse) -> bool {
+        let rejected = matches!(&self.glob, Some(glob) if !glob.matches(&file.name))
+            || matches!(&self.regex, Some(regex) if !regex.is_match(&file.name))
+            || matches!(self.min_size, Some(min_size) if file.size < min_size)
+            || matches!(self.max_size, Some(max_size) if file.size > max_size)
+            || matches!(self.modified_since, Some(since) if file.modified < since)
+            || matches!(&self.after, Some(after) if self.compare(file, &after.file()).is_le());
+
+        !rejected
+    }
+
+    /// Whether all matching files have to be collected before any is sent.
+    pub fn needs_sorting(&self) -> bool {
+        self.sort_by != SortBy::None
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Collects matching files in order. Only as many as the limit or the
+    /// page and one more to tell if there's a next page are kept, so that
+    /// memory doesn't grow with the directory.
+    pub fn sorted_files(&self) -> SortedFiles<'_> {
+        let capacity = match (self.page_size, self.limit) {
+            (Some(page_size), _) => Some(page_size + 1),
+            (None, limit) => limit,
+        };
+
+        SortedFiles {
+            filter: self,
+            capacity,
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    fn compare(&self, a: &ListFilesResponse, b: &ListFilesResponse) -> Ordering {
+        let ordering = match self.sort_by {
+            SortBy::None => Ordering::Equal,
+            SortBy::Name => a.name.cmp(&b.name),
+            SortBy::Size => a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name)),
+            SortBy::Modified => a
+                .modified
+                .cmp(&b.modified)
+                .then_with(|| a.name.cmp(&b.name)),
+        };
+
+        if self.reverse {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+/// Files sorted by [`ListFilter`], the last one in order on top of the heap.
+pub struct SortedFiles<'a> {
+    filter: &'a ListFilter,
+    capacity: Option<usize>,
+    heap: BinaryHeap<Ranked<'a>>,
+}
+
+impl SortedFiles<'_> {
+    pub fn push(&mut self, file: ListFilesResponse) {
+        self.heap.push(Ranked {
+            filter: self.filter,
+            file,
+        });
+
+        if matches!(self.capacity, Some(capacity) if self.heap.len() > capacity) {
+            self.heap.pop();
+        }
+    }
+
+    /// Files of the page in order, with token of the next page if there is
+    /// one.
+    pub fn finish(self) -> (Vec<ListFilesResponse>, Option<String>) {
+        let filter = self.filter;
+        let mut files: Vec<_> = self
+            .heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|ranked| ranked.file)
+            .collect();
+
+        let Some(page_size) = filter.page_size else {
+            return (files, None);
+        };
+        if files.len() <= page_size {
+            return (files, None);
+        }
+        files.truncate(page_size);
+
+        let next_page_token = files.last().map(|last| {
+            PageToken {
+                sort_by: filter.sort_by as i32,
+                reverse: filter.reverse,
+                listing: filter.listing,
+                name: last.name.clone(),
+                size: last.size,
+                modified: last.modified,
+            }
+            .encode()
+        });
+
+        (files, next_page_token)
+    }
+}
+
+/// File ordered by the filter's sort key.
+struct Ranked<'a> {
+    filter: &'a ListFilter,
+    file: ListFilesResponse,
+}
+
+impl Ord for Ranked<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.filter.compare(&self.file, &other.file)
+    }
+}
+
+impl PartialOrd for Ranked<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ranked<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Ranked<'_> {}
+
+/// Identifies the listing which a page token continues, page size may
+/// change between pages. SHA-256 of the JSON encoding is stable across
+/// builds, so tokens survive server upgrades.
+fn listing_hash(request: &ListFilesRequest) -> u64 {
+    let listing = serde_json::to_vec(&(
+        &request.share,
+        &request.glob,
+        &request.regex,
+        request.min_size,
+        request.max_size,
+        request.modified_since,
+    ))
+    .unwrap_or_default();
+    let digest = Sha256::digest(listing);
+
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, size: u64) -> ListFilesResponse {
+        ListFilesResponse {
+            name: name.to_string(),
+            size,
+            ..Default::default()
+        }
+    }
+
+    /// Names on the page of `request` and token of the next one.
+    fn page(request: &ListFilesRequest) -> (Vec<String>, Option<String>) {
+        let filter = ListFilter::new(request).unwrap();
+        let mut sorted_files = filter.sorted_files();
+        for file in [file("a", 3), file("b", 2), file("c", 1)] {
+            if filter.matches(&file) {
+                sorted_files.push(file);
+            }
+        }
+        let (files, next_page_token) = sorted_files.finish();
+        let names = files.into_iter().map(|file| file.name).collect();
+        (names, next_page_token)
+    }
+
+    fn first_page_token(request: &ListFilesRequest) -> String {
+        page(request).1.unwrap()
+    }
+
+    #[test]
+    fn page_token_continues_listing() {
+        let mut request = ListFilesRequest {
+            page_size: 2,
+            sort_by: SortBy::Size as i32,
+            ..Default::default()
+        };
+
+        let (names, page_token) = page(&request);
+        assert_eq!(names, ["c", "b"]);
+
+        request.page_token = page_token.unwrap();
+        let (names, page_token) = page(&request);
+        assert_eq!(names, ["a"]);
+        assert_eq!(page_token, None);
+    }
+
+    #[test]
+    fn page_token_rejects_tampering() {
+        let request = ListFilesRequest {
+            page_size: 1,
+            ..Default::default()
+        };
+        let page_token = first_page_token(&request);
+
+        let mut token = PageToken::decode(&page_token).unwrap();
+        token.listing ^= 1;
+        let forged = token.encode();
+
+        for (page_token, error) in [
+            (forged, "doesn't match"),
+            (format!("{page_token}!"), "Invalid page token"),
+            ("e30".to_string(), "Invalid page token"),
+        ] {
+            let err = ListFilter::new(&ListFilesRequest {
+                page_token,
+                ..request.clone()
+            })
+            .unwrap_err();
+            assert_eq!(err.code(), tonic::Code::InvalidArgument);
+            assert!(err.message().contains(error), "{err:?}");
+        }
+    }
+
+    #[test]
+    fn listing_hash_is_stable() {
+        // Tokens issued by earlier builds must still be accepted
+        let request = ListFilesRequest {
+            share: "docs".to_string(),
+            glob: "*.log".to_string(),
+            max_size: Some(1024),
+            ..Default::default()
+        };
+        assert_eq!(listing_hash(&request), 14029543010074208725);
+    }
+
+    #[test]
+    fn page_token_rejects_other_listing() {
+        let request = ListFilesRequest {
+            page_size: 1,
+            sort_by: SortBy::Size as i32,
+            ..Default::default()
+        };
+        let page_token = first_page_token(&request);
+
+        for replayed in [
+            ListFilesRequest {
+                sort_by: SortBy::Name as i32,
+                ..request.clone()
+            },
+            ListFilesRequest {
+                reverse: true,
+                ..request.clone()
+            },
+            ListFilesRequest {
+                glob: "*.log".to_string(),
+                ..request.clone()
+            },
+            ListFilesRequest {
+                share: "docs".to_string(),
+                ..request.clone()
+            },
+        ] {
+            let err = ListFilter::new(&ListFilesRequest {
+                page_token: page_token.clone(),
+                ..replayed
+            })
+            .unwrap_err();
+            assert_eq!(err.code(), tonic::Code::InvalidArgument);
+            assert!(err.message().contains("doesn't match"), "{err:?}");
+        }
+
+        // Page size may change between pages
+        assert!(ListFilter::new(&ListFilesRequest {
+            page_size: 2,
+            page_token,
+            ..request
+        })
+        .is_ok());
+    }
+}
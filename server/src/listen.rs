@@ -0,0 +1,176 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display},
+    net::SocketAddr,
+    os::unix::{fs::FileTypeExt, io::OwnedFd},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tokio::net::{TcpListener, UnixListener};
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::transport::server::TcpIncoming;
+
+/// Address the server accepts connections on, either `IP:PORT` or
+/// `unix:PATH` for a Unix domain socket. TCP addresses can be prefixed with
+/// `https://` or `http://` to enable or disable TLS just for them.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ListenAddress {
+    Tcp {
+        address: SocketAddr,
+        /// Follows server wide `insecure` setting if not set
+        tls: Option<bool>,
+    },
+    Unix(PathBuf),
+}
+
+impl ListenAddress {
+    /// Sets TLS of TCP address which doesn't choose it explicitly.
+    pub fn resolve_tls(self, insecure: bool) -> Self {
+        match self {
+            Self::Tcp { address, tls } => Self::Tcp {
+                address,
+                tls: Some(tls.unwrap_or(!insecure)),
+            },
+            unix => unix,
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(
+            self,
+            Self::Tcp {
+                tls: Some(true),
+                ..
+            }
+        )
+    }
+}
+
+impl FromStr for ListenAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(anyhow!("missing socket path in {s:?}"));
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+
+        let (address, tls) = if let Some(address) = s.strip_prefix("https://") {
+            (address, Some(true))
+        } else if let Some(address) = s.strip_prefix("http://") {
+            (address, Some(false))
+        } else {
+            (s, None)
+        };
+
+        let address = address.parse().map_err(|_| {
+            anyhow!("invalid listen address {s:?}, expected [http://|https://]IP:PORT or unix:PATH")
+        })?;
+
+        Ok(Self::Tcp { address, tls })
+    }
+}
+
+impl TryFrom<String> for ListenAddress {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<ListenAddress> for String {
+    fn from(address: ListenAddress) -> Self {
+        address.to_string()
+    }
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp { address, tls: None } => write!(f, "{address}"),
+            Self::Tcp {
+                address,
+                tls: Some(true),
+            } => write!(f, "https://{address}"),
+            Self::Tcp {
+                address,
+                tls: Some(false),
+            } => write!(f, "http://{address}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Listening socket ready to be served.
+pub enum Incoming {
+    Tcp {
+        incoming: TcpIncoming,
+        local_addr: SocketAddr,
+        tls: bool,
+    },
+    Unix {
+        incoming: UnixListenerStream,
+        path: PathBuf,
+    },
+}
+
+impl Incoming {
+    pub async fn bind(listen_address: ListenAddress) -> Result<Self> {
+        match listen_address {
+            ListenAddress::Tcp { address, tls } => {
+                Self::from_tcp(TcpListener::bind(address).await?, tls == Some(true))
+            }
+            ListenAddress::Unix(path) => Ok(Self::Unix {
+                incoming: UnixListenerStream::new(bind_unix(&path)?),
+                path,
+            }),
+        }
+    }
+
+    /// Takes over already listening socket, e.g. passed by systemd.
+    pub fn from_fd(listen_address: ListenAddress, fd: OwnedFd) -> Result<Self> {
+        match listen_address {
+            ListenAddress::Tcp { tls, .. } => {
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Self::from_tcp(TcpListener::from_std(listener)?, tls == Some(true))
+            }
+            ListenAddress::Unix(path) => {
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(Self::Unix {
+                    incoming: UnixListenerStream::new(UnixListener::from_std(listener)?),
+                    path,
+                })
+            }
+        }
+    }
+
+    fn from_tcp(listener: TcpListener, tls: bool) -> Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let incoming = TcpIncoming::from_listener(listener, true, None).map_err(|e| anyhow!(e))?;
+
+        Ok(Self::Tcp {
+            incoming,
+            local_addr,
+            tls,
+        })
+    }
+}
+
+/// Binds Unix domain socket, replacing socket file left by previous run.
+fn bind_unix(path: &Path) -> Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("{path:?} exists and is not a socket"));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    Ok(UnixListener::bind(path)?)
+}
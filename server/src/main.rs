@@ -1,20 +1,30 @@
 use anyhow::Result;
 use clap::Parser;
-use server::{cli::Cli, server_main};
+use common::logging::{self, Console};
+use server::{cli::Cli, config::Config, server_main, systemd};
+use std::os::unix::io::OwnedFd;
+
+fn main() -> Result<()> {
+    // Before the runtime starts its threads, as the environment is changed
+    let listen_fds = systemd::take_listen_fds();
+
+    run(listen_fds)
+}
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn run(listen_fds: Vec<OwnedFd>) -> Result<()> {
     let args = Cli::parse();
 
-    tracing_subscriber::fmt()
-        .with_file(true)
-        .with_line_number(true)
-        .with_thread_ids(true)
-        .with_target(false)
-        .with_max_level(args.verbose)
-        .init();
+    let config = Config::load(&args, &listen_fds)?;
+
+    if args.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+
+    logging::init(&config.log, Console::Stdout)?;
 
-    server_main(&args).await?;
+    server_main(&config, listen_fds).await?;
 
     Ok(())
 }
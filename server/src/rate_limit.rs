@@ -0,0 +1,272 @@
+use http::{Request, Response};
+use http_body::Body;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tonic::body::BoxBody;
+use tonic::metadata::MetadataValue;
+use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo};
+use tonic::Status;
+use tower::{Layer, Service};
+use tracing::warn;
+
+/// Limits of calls handled by the server, per peer IP address and in total.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateLimits {
+    pub max_concurrent: Option<usize>,
+    pub max_concurrent_per_peer: Option<usize>,
+    pub max_rate: Option<u32>,
+    pub max_rate_per_peer: Option<u32>,
+}
+
+/// Calls in progress and calls started in the current one second window.
+#[derive(Debug)]
+struct Usage {
+    active: usize,
+    window_start: Instant,
+    window_calls: u32,
+}
+
+impl Usage {
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    fn new(now: Instant) -> Self {
+        Self {
+            active: 0,
+            window_start: now,
+            window_calls: 0,
+        }
+    }
+
+    /// Returns time after which the call should be retried if any limit
+    /// is reached.
+    fn check(
+        &mut self,
+        now: Instant,
+        max_concurrent: Option<usize>,
+        max_rate: Option<u32>,
+    ) -> Option<Duration> {
+        if now.duration_since(self.window_start) >= Self::WINDOW {
+            self.window_start = now;
+            self.window_calls = 0;
+        }
+
+        if matches!(max_rate, Some(max_rate) if self.window_calls >= max_rate) {
+            return Some(Self::WINDOW - now.duration_since(self.window_start));
+        }
+        if matches!(max_concurrent, Some(max_concurrent) if self.active >= max_concurrent) {
+            return Some(Self::WINDOW);
+        }
+
+        None
+    }
+
+    fn is_idle(&self, now: Instant) -> bool {
+        self.active == 0 && now.duration_since(self.window_start) >= Self::WINDOW
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    limits: RateLimits,
+    total: Usage,
+    peers: HashMap<Option<IpAddr>, Usage>,
+}
+
+impl State {
+    fn acquire(&mut self, peer: Option<IpAddr>) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.peers.retain(|_, usage| !usage.is_idle(now));
+
+        let limits = self.limits;
+        let peer_usage = self.peers.entry(peer).or_insert_with(|| Usage::new(now));
+
+        let retry_after = peer_usage
+            .check(
+                now,
+                limits.max_concurrent_per_peer,
+                limits.max_rate_per_peer,
+            )
+            .or_else(|| {
+                self.total
+                    .check(now, limits.max_concurrent, limits.max_rate)
+            });
+        if let Some(retry_after) = retry_after {
+            return Err(retry_after);
+        }
+
+        for usage in [peer_usage, &mut self.total] {
+            usage.active += 1;
+            usage.window_calls += 1;
+        }
+
+        Ok(())
+    }
+
+    fn release(&mut self, peer: Option<IpAddr>) {
+        self.total.active -= 1;
+        if let Some(usage) = self.peers.get_mut(&peer) {
+            usage.active -= 1;
+        }
+    }
+}
+
+/// Tower layer rejecting calls over the limits with `RESOURCE_EXHAUSTED`
+/// and `retry-after` metadata in seconds.
+#[derive(Clone, Debug)]
+pub struct RateLimitLayer {
+    state: Arc<Mutex<State>>,
+}
+
+impl RateLimitLayer {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                limits,
+                total: Usage::new(Instant::now()),
+                peers: HashMap::new(),
+            })),
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimit<S> {
+    inner: S,
+    state: Arc<Mutex<State>>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RateLimit<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+    ResBody: Body<Data = bytes::Bytes, Error = Status> + Unpin + Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let peer = peer_ip(&request);
+
+        let acquired = self.state.lock().unwrap().acquire(peer);
+        if let Err(retry_after) = acquired {
+            warn!(?peer, path = %request.uri().path(), "Rate limit exceeded");
+            return Box::pin(async move { Ok(rejection(retry_after)) });
+        }
+
+        let permit = Permit {
+            state: Arc::clone(&self.state),
+            peer,
+        };
+
+        // Use the service which was polled ready, leaving a fresh clone behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let response = inner.call(request).await?;
+
+            // Streaming responses keep the permit until the body is finished
+            Ok(response.map(|body| {
+                PermitBody {
+                    body,
+                    _permit: permit,
+                }
+                .boxed_unsync()
+            }))
+        })
+    }
+}
+
+fn peer_ip<B>(request: &Request<B>) -> Option<IpAddr> {
+    let extensions = request.extensions();
+
+    extensions
+        .get::<TcpConnectInfo>()
+        .or_else(|| {
+            extensions
+                .get::<TlsConnectInfo<TcpConnectInfo>>()
+                .map(|info| info.get_ref())
+        })
+        .and_then(|info| info.remote_addr())
+        .map(|address| address.ip())
+}
+
+fn rejection(retry_after: Duration) -> Response<BoxBody> {
+    let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+
+    let mut status = Status::resource_exhausted("Too many requests, retry later");
+    status
+        .metadata_mut()
+        .insert("retry-after", MetadataValue::from(seconds));
+
+    status.to_http()
+}
+
+/// Slot taken by a call, given back when dropped.
+struct Permit {
+    state: Arc<Mutex<State>>,
+    peer: Option<IpAddr>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().release(self.peer);
+    }
+}
+
+struct PermitBody<B> {
+    body: B,
+    _permit: Permit,
+}
+
+impl<B> Body for PermitBody<B>
+where
+    B: Body + Unpin,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.body).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.body.size_hint()
+    }
+}
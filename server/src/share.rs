@@ -0,0 +1,187 @@
+use crate::audit::Peer;
+use clap::ValueEnum;
+use proto::api::{self, GetCapabilitiesResponse, ShareInfo};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tonic::Status;
+
+/// Operations allowed on a share.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShareMode {
+    #[default]
+    ReadWrite,
+    ReadOnly,
+    /// Drop box, clients can upload but not list or download
+    WriteOnly,
+}
+
+impl ShareMode {
+    pub fn can_read(self) -> bool {
+        self != Self::WriteOnly
+    }
+
+    pub fn can_write(self) -> bool {
+        self != Self::ReadOnly
+    }
+}
+
+impl From<ShareMode> for api::ShareMode {
+    fn from(mode: ShareMode) -> Self {
+        match mode {
+            ShareMode::ReadWrite => api::ShareMode::ReadWrite,
+            ShareMode::ReadOnly => api::ShareMode::ReadOnly,
+            ShareMode::WriteOnly => api::ShareMode::WriteOnly,
+        }
+    }
+}
+
+/// Directory exported by the server under a name.
+#[derive(Debug)]
+pub struct Share {
+    pub name: String,
+    pub directory: PathBuf,
+    pub mode: ShareMode,
+    pub quota: Option<u64>,
+    pub acl: Vec<String>,
+}
+
+impl Share {
+    /// Resolves file name relative to share directory, rejecting paths
+    /// which could escape it.
+    pub fn resolve(&self, name: &str) -> Result<PathBuf, Status> {
+        let relative_path = Path::new(name);
+        let is_normal = relative_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+
+        if name.is_empty() || !is_normal {
+            return Err(Status::invalid_argument(format!(
+                "Invalid file name: {name:?}"
+            )));
+        }
+
+        Ok(self.directory.join(relative_path))
+    }
+
+    /// Resolves directory like `resolve`, empty name being the share
+    /// directory itself.
+    pub fn resolve_directory(&self, name: &str) -> Result<PathBuf, Status> {
+        if name.is_empty() {
+            Ok(self.directory.clone())
+        } else {
+            self.resolve(name)
+        }
+    }
+
+    pub fn check_read(&self) -> Result<(), Status> {
+        if self.mode.can_read() {
+            Ok(())
+        } else {
+            Err(Status::permission_denied(format!(
+                "Share {:?} is write-only",
+                self.name
+            )))
+        }
+    }
+
+    pub fn check_write(&self) -> Result<(), Status> {
+        if self.mode.can_write() {
+            Ok(())
+        } else {
+            Err(Status::permission_denied(format!(
+                "Share {:?} is read-only",
+                self.name
+            )))
+        }
+    }
+
+    pub fn is_allowed(&self, peer: &Peer) -> bool {
+        self.acl.is_empty()
+            || matches!(&peer.identity, Some(identity) if self.acl.contains(identity))
+    }
+
+    /// Total size of files in the share directory and its subdirectories.
+    /// Symbolic links aren't followed, so nothing outside of the share is
+    /// counted.
+    pub async fn usage(&self) -> io::Result<u64> {
+        let mut usage = 0u64;
+        let mut pending = vec![self.directory.clone()];
+
+        while let Some(directory) = pending.pop() {
+            let mut dir_stream = fs::read_dir(&directory).await?;
+
+            while let Some(dir_entry) = dir_stream.next_entry().await? {
+                let metadata = fs::symlink_metadata(dir_entry.path()).await?;
+                if metadata.is_dir() {
+                    pending.push(dir_entry.path());
+                } else if metadata.is_file() {
+                    usage = usage.saturating_add(metadata.len());
+                }
+            }
+        }
+
+        Ok(usage)
+    }
+
+    pub fn info(&self) -> ShareInfo {
+        ShareInfo {
+            name: self.name.clone(),
+            quota: self.quota.unwrap_or(0),
+            mode: api::ShareMode::from(self.mode).into(),
+        }
+    }
+
+    pub fn capabilities(&self) -> GetCapabilitiesResponse {
+        GetCapabilitiesResponse {
+            mode: api::ShareMode::from(self.mode).into(),
+            list: self.mode.can_read(),
+            download: self.mode.can_read(),
+            upload: self.mode.can_write(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Shares {
+    shares: BTreeMap<String, Arc<Share>>,
+}
+
+impl Shares {
+    /// Share selected by requests which don't name any.
+    pub const DEFAULT_SHARE: &str = "default";
+
+    pub fn new(shares: impl IntoIterator<Item = Share>) -> Self {
+        Self {
+            shares: shares
+                .into_iter()
+                .map(|share| (share.name.clone(), Arc::new(share)))
+                .collect(),
+        }
+    }
+
+    /// Looks up share by name and checks if peer is allowed to access it.
+    pub fn get(&self, name: &str, peer: &Peer) -> Result<Arc<Share>, Status> {
+        let name = if name.is_empty() {
+            Self::DEFAULT_SHARE
+        } else {
+            name
+        };
+
+        match self.shares.get(name) {
+            Some(share) if share.is_allowed(peer) => Ok(Arc::clone(share)),
+            Some(_) => Err(Status::permission_denied(format!(
+                "Access to share {name:?} denied"
+            ))),
+            None => Err(Status::not_found(format!("Share {name:?} not found"))),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Share>> {
+        self.shares.values()
+    }
+}
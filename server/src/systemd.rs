@@ -0,0 +1,92 @@
+use crate::listen::ListenAddress;
+use anyhow::{anyhow, Context, Result};
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::{
+    env,
+    mem::ManuallyDrop,
+    net::TcpListener,
+    os::unix::{
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        net::UnixListener,
+    },
+    process,
+};
+
+/// First file descriptor passed by socket activation, see sd_listen_fds(3).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// File descriptors passed by systemd to this process.
+fn listen_fds() -> impl Iterator<Item = RawFd> {
+    let for_this_process = matches!(
+        env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()),
+        Some(pid) if pid == process::id()
+    );
+    let count = match env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok())
+    {
+        Some(count) if for_this_process => count,
+        _ => 0,
+    };
+
+    LISTEN_FDS_START..LISTEN_FDS_START + count
+}
+
+/// Takes over sockets passed by systemd and clears their variables from the
+/// environment. It must be called before any thread is started, as changing
+/// the environment isn't thread safe.
+pub fn take_listen_fds() -> Vec<OwnedFd> {
+    let fds = listen_fds()
+        // Safety: systemd passes the descriptors to this process only, they
+        // are taken over once as the environment is cleared right after
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        .collect();
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    fds
+}
+
+/// Addresses of sockets taken over by [`take_listen_fds`], in their order.
+pub fn listen_addresses(fds: &[OwnedFd]) -> Result<Vec<ListenAddress>> {
+    fds.iter()
+        .map(|fd| socket_address(fd.as_raw_fd()))
+        .collect()
+}
+
+fn socket_address(fd: RawFd) -> Result<ListenAddress> {
+    // Safety: the listeners are never dropped, so the descriptor stays open
+    let tcp_listener = ManuallyDrop::new(unsafe { TcpListener::from_raw_fd(fd) });
+    if let Ok(address) = tcp_listener.local_addr() {
+        return Ok(ListenAddress::Tcp { address, tls: None });
+    }
+
+    let unix_listener = ManuallyDrop::new(unsafe { UnixListener::from_raw_fd(fd) });
+    unix_listener
+        .local_addr()
+        .with_context(|| format!("invalid socket passed as file descriptor {fd}"))?
+        .as_pathname()
+        .map(|path| ListenAddress::Unix(path.to_path_buf()))
+        .ok_or_else(|| anyhow!("unsupported socket passed as file descriptor {fd}"))
+}
+
+/// Sends state, e.g. `READY=1`, to the service manager if `NOTIFY_SOCKET`
+/// is set, see sd_notify(3).
+pub fn notify(state: &str) -> Result<()> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    // Leading `@` stands for the abstract namespace
+    let path = match path.to_str().and_then(|path| path.strip_prefix('@')) {
+        Some(name) => format!("\0{name}").into(),
+        None => path,
+    };
+
+    let socket = Socket::new(Domain::UNIX, Type::DGRAM, None)?;
+    socket.send_to(state.as_bytes(), &SockAddr::unix(path)?)?;
+
+    Ok(())
+}
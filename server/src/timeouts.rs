@@ -0,0 +1,147 @@
+use anyhow::anyhow;
+use http::{Request, Response};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::{
+    sync::mpsc::{error::SendTimeoutError, Sender},
+    time::timeout,
+};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{body::BoxBody, transport::Server, Status};
+use tower::{Layer, Service};
+
+/// Calls whose response starts only once the client sent its whole stream,
+/// which may take any time. The idle timeout limits them instead.
+const CLIENT_STREAMING: &[&str] = &[
+    "/file.FileService/UploadFile",
+    "/file.FileService/UploadArchive",
+    "/file.FileService/UploadDelta",
+    "/file.FileService/DownloadDelta",
+];
+
+/// Limits on how long calls and connections may stall.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timeouts {
+    /// Deadline of a unary or server streaming call until its response
+    /// starts
+    pub request: Option<Duration>,
+    /// Longest time a stream may go without any message
+    pub idle: Option<Duration>,
+    pub keepalive_interval: Option<Duration>,
+    pub keepalive_timeout: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn apply<L>(&self, server: Server<L>) -> Server<L> {
+        server
+            .http2_keepalive_interval(self.keepalive_interval)
+            .http2_keepalive_timeout(self.keepalive_timeout)
+    }
+
+    pub fn request_timeout_layer(&self) -> RequestTimeoutLayer {
+        RequestTimeoutLayer {
+            timeout: self.request,
+        }
+    }
+}
+
+/// Tower layer cancelling calls whose response doesn't start within the
+/// request timeout, except for client streams.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestTimeoutLayer {
+    timeout: Option<Duration>,
+}
+
+impl<S> Layer<S> for RequestTimeoutLayer {
+    type Service = RequestTimeout<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestTimeout {
+            inner,
+            timeout: self.timeout,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RequestTimeout<S> {
+    inner: S,
+    timeout: Option<Duration>,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for RequestTimeout<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let deadline = self
+            .timeout
+            .filter(|_| !CLIENT_STREAMING.contains(&request.uri().path()));
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            match deadline {
+                Some(deadline) => timeout(deadline, response)
+                    .await
+                    .unwrap_or_else(|_| Ok(Status::cancelled("Timeout expired").to_http())),
+                None => response.await,
+            }
+        })
+    }
+}
+
+/// Receives next message of a client stream, failing if the client sends
+/// nothing for `idle`.
+pub async fn next_message<S>(
+    stream: &mut S,
+    idle: Option<Duration>,
+) -> Result<Option<S::Item>, Status>
+where
+    S: Stream + Unpin,
+{
+    match idle {
+        Some(idle) => timeout(idle, stream.next()).await.map_err(|_| {
+            Status::deadline_exceeded(format!(
+                "No data received for {}",
+                humantime::format_duration(idle)
+            ))
+        }),
+        None => Ok(stream.next().await),
+    }
+}
+
+/// Sends message to a client stream, failing if the client disconnected or
+/// doesn't take it for `idle`.
+pub async fn send_message<T>(
+    tx: &Sender<T>,
+    message: T,
+    idle: Option<Duration>,
+) -> anyhow::Result<()> {
+    let result = match idle {
+        Some(idle) => tx.send_timeout(message, idle).await,
+        None => tx
+            .send(message)
+            .await
+            .map_err(|err| SendTimeoutError::Closed(err.0)),
+    };
+
+    match (result, idle) {
+        (Ok(()), _) => Ok(()),
+        (Err(SendTimeoutError::Timeout(_)), Some(idle)) => Err(anyhow!(
+            "Client received nothing for {}",
+            humantime::format_duration(idle)
+        )),
+        (Err(_), _) => Err(anyhow!("Client disconnected")),
+    }
+}
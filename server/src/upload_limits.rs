@@ -0,0 +1,154 @@
+use crate::share::Share;
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tonic::Status;
+
+/// Server wide limits applied to every upload.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UploadLimits {
+    pub max_file_size: Option<u64>,
+    /// Free space which uploads must leave on the volume
+    pub min_free_space: u64,
+}
+
+/// Checks single upload against upload limits, share quota and free disk space.
+pub struct UploadGuard {
+    limits: UploadLimits,
+    directory: PathBuf,
+    declared_size: Option<u64>,
+    quota_available: Option<u64>,
+}
+
+impl UploadGuard {
+    /// Rejects upload up front if its declared size doesn't fit.
+    pub async fn new(
+        limits: UploadLimits,
+        share: &Share,
+        file_path: &Path,
+        declared_size: Option<u64>,
+    ) -> Result<Self> {
+        let usage = ShareUsage::new(share).await?;
+        let replaced = replaced_size(file_path).await;
+
+        Self::with_usage(limits, share, &usage, replaced, declared_size).await
+    }
+
+    /// Like `new`, with usage of the share counted already and size of the
+    /// replaced file, which is counted as free.
+    pub async fn with_usage(
+        limits: UploadLimits,
+        share: &Share,
+        usage: &ShareUsage,
+        replaced: u64,
+        declared_size: Option<u64>,
+    ) -> Result<Self> {
+        let guard = Self {
+            limits,
+            directory: share.directory.clone(),
+            declared_size,
+            quota_available: usage.available(replaced),
+        };
+
+        // Free space isn't checked up front, holes of sparse files take none
+        if let Some(size) = declared_size {
+            guard.check_limits(size, &share.name)?;
+        }
+
+        Ok(guard)
+    }
+
+    /// Checks if next chunk can be written after `written` bytes.
+    pub async fn check(&self, written: u64, chunk_size: u64, share_name: &str) -> Result<()> {
+        self.check_hole(written, chunk_size, share_name)?;
+        self.check_free_space(chunk_size).await
+    }
+
+    /// Checks if a hole of `len` bytes can follow `written` bytes. Holes
+    /// count towards file size and quota, but take no disk space.
+    pub fn check_hole(&self, written: u64, len: u64, share_name: &str) -> Result<()> {
+        let size = written
+            .checked_add(len)
+            .ok_or_else(|| Status::invalid_argument("Uploaded data is too large"))?;
+
+        if matches!(self.declared_size, Some(declared_size) if size > declared_size) {
+            Err(Status::invalid_argument(
+                "Uploaded data exceeds declared file size",
+            ))?;
+        }
+
+        self.check_limits(size, share_name)
+    }
+
+    fn check_limits(&self, size: u64, share_name: &str) -> Result<()> {
+        if matches!(self.limits.max_file_size, Some(max_file_size) if size > max_file_size) {
+            Err(Status::resource_exhausted(format!(
+                "File exceeds maximum size of {} bytes",
+                self.limits.max_file_size.unwrap()
+            )))?;
+        }
+
+        if matches!(self.quota_available, Some(available) if size > available) {
+            Err(Status::resource_exhausted(format!(
+                "Share {share_name:?} quota exceeded"
+            )))?;
+        }
+
+        Ok(())
+    }
+
+    async fn check_free_space(&self, size: u64) -> Result<()> {
+        let directory = self.directory.clone();
+        let available_space =
+            tokio::task::spawn_blocking(move || fs2::available_space(directory)).await??;
+
+        if available_space < size.saturating_add(self.limits.min_free_space) {
+            Err(Status::resource_exhausted("Not enough free disk space"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Space taken by files of a share with a quota, counted once and updated
+/// as files are written, so that many files can be checked without walking
+/// the share for each.
+pub struct ShareUsage {
+    quota: Option<u64>,
+    used: u64,
+}
+
+impl ShareUsage {
+    pub async fn new(share: &Share) -> Result<Self> {
+        let used = match share.quota {
+            Some(_) => share.usage().await?,
+            None => 0,
+        };
+
+        Ok(Self {
+            quota: share.quota,
+            used,
+        })
+    }
+
+    /// Returns how many bytes can be written to a file without exceeding
+    /// share quota, counting `replaced` bytes of its previous version as
+    /// free.
+    fn available(&self, replaced: u64) -> Option<u64> {
+        self.quota
+            .map(|quota| quota.saturating_sub(self.used.saturating_sub(replaced)))
+    }
+
+    /// Accounts for a file of `written` bytes which replaced `replaced` bytes.
+    pub fn update(&mut self, replaced: u64, written: u64) {
+        self.used = self.used.saturating_sub(replaced).saturating_add(written);
+    }
+}
+
+/// Size of the file which an upload to `file_path` replaces.
+pub async fn replaced_size(file_path: &Path) -> u64 {
+    match fs::metadata(file_path).await {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => 0,
+    }
+}